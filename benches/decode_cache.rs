@@ -0,0 +1,91 @@
+use rust16vm::{
+    asm::{assemble, image_words},
+    machine::{Machine, Register},
+    memory::LinearMemory,
+    run::RunConfig,
+    timing::CycleCosts,
+};
+// cargo bench --bench decode_cache
+//
+// runs every program in testdata with and without the decoded
+// instruction cache, and with the block engine (step_block), and
+// compares the time per executed instruction
+use std::{fs, path::Path, time::Instant};
+
+// programs that never halt are cut here
+const MAX_STEPS: u64 = 200_000;
+const RUNS: u32 = 20;
+
+fn main() {
+    let mut paths: Vec<_> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata"))
+        .expect("reading testdata")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "s"))
+        .collect();
+    paths.sort();
+
+    println!(
+        "{:<16} {:>10} {:>14} {:>14} {:>8} {:>14} {:>8}",
+        "program", "steps", "uncached ns", "cached ns", "speedup", "blocks ns", "speedup"
+    );
+
+    for path in paths {
+        let source = fs::read_to_string(&path).expect("reading program");
+        let program = match assemble(&path.to_string_lossy(), &source) {
+            Ok(image) => image_words(&image),
+            Err(err) => {
+                println!("{:<16} skipped: {} assembler errors", file_name(&path), err.len());
+                continue;
+            }
+        };
+
+        let (steps, uncached) = bench(&program, false, false);
+        let (_, cached) = bench(&program, true, false);
+        let (_, blocks) = bench(&program, true, true);
+
+        println!(
+            "{:<16} {:>10} {:>14.2} {:>14.2} {:>7.2}x {:>14.2} {:>7.2}x",
+            file_name(&path),
+            steps,
+            uncached,
+            cached,
+            uncached / cached,
+            blocks,
+            uncached / blocks
+        );
+    }
+}
+
+// bench returns the executed instructions of a single run
+// and the average time per instruction in nanoseconds
+fn bench(program: &[u16], cached: bool, blocks: bool) -> (u64, f64) {
+    let mut steps = 0;
+    let mut elapsed = 0.0;
+
+    for _ in 0..RUNS {
+        let mut memory = LinearMemory::new(1 << 16);
+        assert!(memory.write_program(program));
+
+        let mut machine = Machine::new(memory);
+        machine.set_decode_cache(cached);
+        machine.set_register(Register::SP, 0xFFFF);
+        // one cycle per instruction, so cycles() counts them
+        machine.set_cycle_costs(CycleCosts::uniform(1));
+
+        // only the execution is measured, not the machine setup
+        let config = RunConfig {
+            blocks,
+            ..RunConfig::with_budget(MAX_STEPS)
+        };
+        let start = Instant::now();
+        machine.run(&config);
+        elapsed += start.elapsed().as_nanos() as f64;
+        steps = machine.cycles();
+    }
+
+    (steps, elapsed / (steps.max(1) as f64 * RUNS as f64))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
@@ -1,37 +1,56 @@
-use rust16vm::{
-    asm::{encode_instruction, resolve_and_parse_assembly},
-    machine::Instruction,
-};
+use rust16vm::asm::assemble_sources;
 #[allow(dead_code)]
-// asm [output] [input files...]
+// asm [-I dir]... [output] [input files...]
 
-// asm file.s file.bin -> outputs the encoded instructions
-// asm file.s file.S -> will resolve the labels from .s file
-// asm file.S file.bin -> outputs the encoded instructions
+// asm file.bin file.s -> outputs the encoded instructions
+// asm file.bin main.s lib.s -> one program, the files share their labels
+// asm -I lib file.bin file.s -> `.include` also looks into lib
 use std::env;
 use std::{
     fs::File,
     io::{BufReader, Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 fn main() -> Result<(), ()> {
-    let args: Vec<String> = env::args().collect();
+    let mut include_paths = vec![];
+    let mut positional = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-I" {
+            match args.next() {
+                Some(dir) => include_paths.push(PathBuf::from(dir)),
+                None => {
+                    eprintln!("expected a directory after -I");
+                    return Err(());
+                }
+            }
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(PathBuf::from(dir));
+        } else {
+            positional.push(arg);
+        }
+    }
 
-    if args.len() != 3 {
-        eprintln!("expected 2 positional args, received {}", args.len() - 1);
+    if positional.len() < 2 {
+        eprintln!(
+            "expected an output file and at least an input file, received {} args",
+            positional.len()
+        );
         return Err(());
     }
 
-    let output_file = match Extension::try_from(args[1].to_string()) {
+    let output_file = match Extension::try_from(positional[0].to_string()) {
         Ok(ext) => ext,
         Err(ext_err) => {
-            eprintln!("checking output file {} extension: {}", args[1], ext_err);
+            eprintln!("checking output file {} extension: {}", positional[0], ext_err);
             return Err(());
         }
     };
 
-    for input in &args[2..] {
+    let mut sources = vec![];
+    for input in &positional[1..] {
         let _input_file = match Extension::try_from(input.to_string()) {
             Ok(ext) => ext,
             Err(ext_err) => {
@@ -40,50 +59,49 @@ fn main() -> Result<(), ()> {
             }
         };
 
-        // place this code into a speciallized method bind to each Extension variant
-        let path = Path::new(input);
-        let open_file = File::open(path);
-        match open_file {
-            Ok(file) => {
-                let mut buf = BufReader::new(file);
-                let mut asm_str = String::new();
-
-                match buf.read_to_string(&mut asm_str) {
-                    Ok(_) => {}
-                    Err(buf_err) => {
-                        eprintln!("while reading input file {}: {}", input, buf_err);
-                        return Err(());
-                    }
-                }
-
-                let instructions = match resolve_and_parse_assembly(
-                    asm_str.as_ref(),
-                ) {
-                    Ok(instructions) => instructions,
-                    Err(err) => {
-                        eprintln!("resolving input file {}: {:?}", input, err);
-                        return Err(());
-                    }
-                };
-
-                match output_file.write_instructions(instructions) {
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        return Err(());
-                    }
-                    Ok(()) => {
-                        println!("generated {} file", args[1]);
-                    }
-                }
-            }
+        let file = match File::open(Path::new(input)) {
+            Ok(file) => file,
             Err(io_err) => {
                 eprintln!("opening {}: {}", input, io_err);
                 return Err(());
             }
+        };
+
+        let mut asm_str = String::new();
+        if let Err(buf_err) = BufReader::new(file).read_to_string(&mut asm_str) {
+            eprintln!("while reading input file {}: {}", input, buf_err);
+            return Err(());
         }
+        sources.push((input.as_str(), asm_str));
     }
 
-    Ok(())
+    let sources: Vec<(&str, &str)> = sources
+        .iter()
+        .map(|(input, code)| (*input, code.as_str()))
+        .collect();
+
+    // the files make a single program, their labels are shared
+    let image = match assemble_sources(&sources, &include_paths) {
+        Ok(image) => image,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", diagnostic);
+            }
+            eprintln!("{}: {} errors", positional[1..].join(", "), diagnostics.len());
+            return Err(());
+        }
+    };
+
+    match output_file.write_image(&image.to_bin()) {
+        Err(err) => {
+            eprintln!("{}", err);
+            Err(())
+        }
+        Ok(()) => {
+            println!("generated {} file", positional[0]);
+            Ok(())
+        }
+    }
 }
 
 enum Extension {
@@ -93,23 +111,17 @@ enum Extension {
 }
 
 impl Extension {
-    pub fn write_instructions(&self, instructions: Vec<Instruction>) -> Result<(), String> {
+    // the binary file holds the memory image, code and data,
+    // behind a header with the spans of the code (see `Image::to_bin`)
+    pub fn write_image(&self, image: &[u8]) -> Result<(), String> {
         match self {
             Extension::BinaryExt(f) => {
-                let encoded: Vec<u16> = instructions
-                    .iter()
-                    .map(|inst| encode_instruction(inst))
-                    .collect();
-
-                let bin_instructions: Vec<u8> =
-                    encoded.iter().flat_map(|inst| inst.to_le_bytes()).collect();
-
                 let mut file = match File::create(f) {
                     Ok(bin_file) => bin_file,
                     Err(err) => return Err(format!("creating binary file: {}", err)),
                 };
 
-                match file.write_all(&bin_instructions) {
+                match file.write_all(image) {
                     Ok(()) => Ok(()),
                     Err(err) => Err(format!("writing to binary file: {}", err)),
                 }
@@ -0,0 +1,114 @@
+use rust16vm::{
+    machine::Register,
+    trace::{TraceEvent, TraceFilter, first_divergence, read_trace},
+};
+// trace show <file> [--pc addr] [--inst mnemonic] [--reg register] [--addr addr] [--from step] [--to step]
+// trace diff <file> <file> [--context n]
+
+// trace show run.trace --inst CALL -> prints every CALL executed
+// trace diff good.trace bad.trace  -> prints where both runs diverge
+use std::{env, fs::File, io::BufReader, process::ExitCode};
+
+// amount of events printed before the divergence
+const DEFAULT_CONTEXT: usize = 5;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+
+    let result = match args.get(1).map(String::as_str) {
+        Some("show") if args.len() >= 3 => show(&args[2], &args[3..]),
+        Some("diff") if args.len() >= 4 => diff(&args[2], &args[3], &args[4..]),
+        _ => Err(String::from(
+            "usage: trace show <file> [filters...] | trace diff <file> <file> [--context n]",
+        )),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn show(path: &str, flags: &[String]) -> Result<ExitCode, String> {
+    let events = load(path)?;
+
+    let mut filter = TraceFilter::default();
+    for pair in flags.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(format!("missing value for {}", pair[0]));
+        };
+
+        match flag.as_str() {
+            "--pc" => filter.pc = Some(parse_number(value)? as u16),
+            "--inst" => filter.mnemonic = Some(value.to_string()),
+            "--reg" => {
+                let reg = value
+                    .to_uppercase()
+                    .parse::<Register>()
+                    .map_err(|_| format!("invalid register: {}", value))?;
+                filter.register = Some(reg);
+            }
+            "--addr" => filter.addr = Some(parse_number(value)? as u16),
+            "--from" => filter.from_step = Some(parse_number(value)?),
+            "--to" => filter.to_step = Some(parse_number(value)?),
+            _ => return Err(format!("unknown flag: {}", flag)),
+        }
+    }
+
+    for event in events.iter().filter(|event| filter.matches(event)) {
+        println!("{}", event);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// diff exits with failure when both traces diverge
+fn diff(lhs_path: &str, rhs_path: &str, flags: &[String]) -> Result<ExitCode, String> {
+    let context = match flags {
+        [] => DEFAULT_CONTEXT,
+        [flag, value] if flag == "--context" => parse_number(value)? as usize,
+        _ => return Err(format!("unknown flags: {}", flags.join(" "))),
+    };
+
+    let lhs = load(lhs_path)?;
+    let rhs = load(rhs_path)?;
+
+    let Some(idx) = first_divergence(&lhs, &rhs) else {
+        println!("traces are identical ({} steps)", lhs.len());
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    println!("traces diverge at step {}", idx);
+    for event in &lhs[idx.saturating_sub(context)..idx] {
+        println!("  {}", event);
+    }
+
+    print_side("<", lhs_path, lhs.get(idx));
+    print_side(">", rhs_path, rhs.get(idx));
+
+    Ok(ExitCode::FAILURE)
+}
+
+fn print_side(marker: &str, path: &str, event: Option<&TraceEvent>) {
+    match event {
+        Some(event) => println!("{} {}", marker, event),
+        None => println!("{} {} ended", marker, path),
+    }
+}
+
+fn load(path: &str) -> Result<Vec<TraceEvent>, String> {
+    let file = File::open(path).map_err(|err| format!("opening {}: {}", path, err))?;
+    read_trace(BufReader::new(file)).map_err(|err| format!("reading {}: {}", path, err))
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse::<u64>(),
+    };
+
+    parsed.map_err(|_| format!("invalid number: {}", value))
+}
@@ -1,32 +1,49 @@
 use std::env::{self};
 use std::fs::File;
-use std::io::{BufReader, Read, prelude::*, stdout};
+use std::io::{BufReader, BufWriter, Read, prelude::*, stdout};
 use std::path::Path;
+use std::process::ExitCode;
 
 use crossterm::terminal as crossterm_terminal;
 use rust16vm::devices::screen::ScreenOptions;
 use rust16vm::devices::terminal::TerminalAction;
-use rust16vm::machine::State;
 use rust16vm::{
-    devices::{keyboard::Keyboard, screen::ScreenDevice, terminal::Terminal256},
+    asm::{Image, image_words},
+    devices::{clock::CycleCounter, keyboard::Keyboard, screen::ScreenDevice, terminal::Terminal256},
     machine::{Machine, Register},
     memory::{self, Addressable, LinearMemory},
     mmio::MemoryWithDevices,
+    run::{RunConfig, StopReason},
     rv16asm,
+    syscall::StdSyscalls,
+    trace::TraceWriter,
 };
 
-pub fn main() -> () {
+// amount of steps the debugger can undo
+const DEBUG_HISTORY: usize = 4096;
+
+pub fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         eprintln!("expected 1 positional arg, received {}", args.len() - 1);
-        return;
+        return ExitCode::FAILURE;
     }
 
-    let is_debug = if let Some(last_arg) = args.last() {
-        last_arg.eq(&String::from("--debug"))
-    } else {
-        false
+    let is_debug = args.iter().any(|arg| arg == "--debug");
+    // --blocks runs with the basic block engine instead of stepping
+    let use_blocks = args.iter().any(|arg| arg == "--blocks");
+
+    // --load <file> resumes from a snapshot and --save-on-halt <file>
+    // stores one when the machine stops (or fails)
+    let flag_value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|idx| args.get(idx + 1))
+            .cloned()
     };
+    let load_path = flag_value("--load");
+    let save_path = flag_value("--save-on-halt");
+    let trace_path = flag_value("--trace");
 
     let path = Path::new(&args[1]);
     let open_file = File::open(path);
@@ -39,24 +56,32 @@ pub fn main() -> () {
                 Ok(_) => {}
                 Err(err) => {
                     eprintln!("reading input program: {}", err);
-                    return;
+                    return ExitCode::FAILURE;
                 }
             }
         }
         Err(err) => {
             eprintln!("opening input program: {}", err);
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    let program: Vec<u16> = input_program
-        .chunks(2)
-        .map(|chunk| (chunk[1] as u16) << 8 | (chunk[0] as u16))
-        .collect();
+    let image = match Image::from_bin(&input_program) {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("reading input program: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let program = image_words(&image.bytes);
 
     let mut memory = LinearMemory::new(1 << 16); //63Kb
 
-    assert!(memory.write_program(&program));
+    // only the code is read-only, the data of the image can be written
+    if !memory.write_image(&image.bytes, &image.code) {
+        eprintln!("the program does not fit in memory");
+        return ExitCode::FAILURE;
+    }
 
     let mut opts = ScreenOptions::default();
     opts.debug_instructions(program.clone(), 0);
@@ -65,13 +90,55 @@ pub fn main() -> () {
     let mut memory = MemoryWithDevices::new(memory);
 
     memory.register_device(terminal, 0xF000, 259).unwrap();
+    // the cycle counter sits right before the interrupt vector table
+    memory.register_device(CycleCounter::new(), 0xEFD8, 8).unwrap();
 
     let mut machine = Machine::new_debug(memory, is_debug);
+    if is_debug {
+        machine.enable_journal(DEBUG_HISTORY);
+    }
+    // SYSCALL prints and reads lines through the vm's own stdio
+    machine.set_syscall_handler(Box::new(StdSyscalls::new(
+        BufReader::new(CookedStdin),
+        CrlfStdout,
+    )));
     // define the stack pointer to the memory end;
     machine.set_register(Register::SP, 0xFFFF);
+    // the stack can grow down until the terminal device region
+    machine.set_stack_region(0xF104, 0xFFFF);
+
+    // the trace file is written as the machine runs, inspect
+    // it with the `trace` binary
+    if let Some(path) = &trace_path {
+        match File::create(path) {
+            Ok(file) => machine.set_trace_sink(Box::new(TraceWriter::new(BufWriter::new(file)))),
+            Err(err) => {
+                eprintln!("creating trace file {}: {}", path, err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Some(path) = &load_path {
+        let restored = std::fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|snapshot| machine.restore(&snapshot).map_err(|err| err.to_string()));
+
+        if let Err(err) = restored {
+            eprintln!("loading snapshot {}: {}", path, err);
+            return ExitCode::FAILURE;
+        }
+    }
 
     let mut stdout = stdout();
     let mut hit_dbg = false;
+    let run_config = RunConfig {
+        blocks: use_blocks,
+        ..RunConfig::default()
+    };
+    let single_step = RunConfig::with_budget(1);
+    // the program's exit code, a fault or any other stop fails
+    let mut status = ExitCode::SUCCESS;
     
     loop {
         if is_debug {
@@ -82,50 +149,32 @@ pub fn main() -> () {
                     TerminalAction::KeyPressed(c) if c == 's' => {
                         hit_dbg = true;
                     },
+                    // step back one instruction
+                    TerminalAction::KeyPressed('b') => {
+                        if machine.step_back() {
+                            machine.print_regs();
+                        } else {
+                            print!("no history to step back\r\n");
+                        }
+                        hit_dbg = true;
+                        continue;
+                    },
+                    // run back until PC reaches the typed address
+                    TerminalAction::KeyPressed('p') => {
+                        print!("run back to pc:\r\n");
+                        let pc: u16 = read_number(&mut stdout).parse().unwrap_or(0);
+                        if !machine.run_back_to(pc) {
+                            print!("pc {} not found in history\r\n", pc);
+                        }
+                        machine.print_regs();
+                        hit_dbg = true;
+                        continue;
+                    },
                     TerminalAction::KeyPressed(c) if c == 'r' => {
                         print!("reading from stack:\r\n");
 
-                        let mut text_mem_address = String::new();
-                        loop {
-                            let action = Terminal256::read_from_stdin();
-                            match action  {
-                                Ok(TerminalAction::NumberPressed(n)) => {
-                                    print!("{}", n);
-                                    stdout.flush().unwrap();
-                                    text_mem_address.push(n);
-                                }
-                                Ok(TerminalAction::KeyPressedBackspace) => {
-                                    // Erase the last character
-                                    print!("\u{8} \u{8}"); // Move cursor back, print space, move cursor back again
-                                    stdout.flush().unwrap(); // Ensure the changes are written
-                                    text_mem_address.remove(text_mem_address.len()-1);
-                                }
-                                Ok(TerminalAction::KeyPressedEnter) => break,
-                                _ => {}
-                            }
-                        }
-                        print!("\r\n");
-
-                        let mut text_size = String::new();
-                        loop {
-                            let action = Terminal256::read_from_stdin();
-                            match action  {
-                                Ok(TerminalAction::NumberPressed(n)) => {
-                                    print!("{}", n);
-                                    stdout.flush().unwrap();
-                                    text_size.push(n);
-                                }
-                                Ok(TerminalAction::KeyPressedBackspace) => {
-                                    // Erase the last character
-                                    print!("\u{8} \u{8}"); // Move cursor back, print space, move cursor back again
-                                    stdout.flush().unwrap(); // Ensure the changes are written
-                                    text_size.remove(text_size.len()-1);
-                                }
-                                Ok(TerminalAction::KeyPressedEnter) => break,
-                                _ => {}
-                            }
-                        }
-                        print!("\r\n");
+                        let text_mem_address = read_number(&mut stdout);
+                        let text_size = read_number(&mut stdout);
 
                         let mem_addr: u16 = text_mem_address.parse().unwrap();
                         let size: u16 = text_size.parse().unwrap();
@@ -150,21 +199,104 @@ pub fn main() -> () {
             }            
         }
 
-        let r = machine.step();
-        match r {
-            Ok(State::Continue) => continue,
-            Ok(State::Stop) => break,
-            Ok(State::Debug) => {
+        // `s` in the debugger executes a single instruction
+        let config = if hit_dbg { &single_step } else { &run_config };
+        match machine.run(config) {
+            StopReason::Halted => {
+                // the host keeps 8 bits, larger codes must not read as 0
+                let code = machine.exit_code().unwrap_or(0);
+                status = ExitCode::from(u8::try_from(code).unwrap_or(u8::MAX));
+                break;
+            }
+            // DBG only pauses the debugger
+            StopReason::DebugTrap(tag) => {
+                if is_debug {
+                    print!("DBG #{}\r\n", tag);
+                    hit_dbg = true;
+                }
+                continue;
+            }
+            StopReason::BudgetExhausted => {
                 hit_dbg = true;
                 continue;
             }
-            Err(err) => {
-                print!("error: {:?}\r\n", err);
+            reason => {
+                print!("{}\r\n", reason);
                 _ = stdout.flush().unwrap();
+                status = ExitCode::FAILURE;
                 break;
             }
         }
     }
 
-    
+    if let Some(path) = &save_path
+        && let Err(err) = std::fs::write(path, machine.snapshot())
+    {
+        print!("saving snapshot {}: {}\r\n", path, err);
+    }
+
+    status
+}
+
+// read_number echoes the typed digits until enter is pressed
+fn read_number(stdout: &mut std::io::Stdout) -> String {
+    let mut text = String::new();
+    loop {
+        let action = Terminal256::read_from_stdin();
+        match action  {
+            Ok(TerminalAction::NumberPressed(n)) => {
+                print!("{}", n);
+                stdout.flush().unwrap();
+                text.push(n);
+            }
+            Ok(TerminalAction::KeyPressedBackspace) => {
+                // Erase the last character
+                print!("\u{8} \u{8}"); // Move cursor back, print space, move cursor back again
+                stdout.flush().unwrap(); // Ensure the changes are written
+                text.pop();
+            }
+            Ok(TerminalAction::KeyPressedEnter) => break,
+            _ => {}
+        }
+    }
+    print!("\r\n");
+
+    text
+}
+
+// CookedStdin reads stdin with the raw mode the terminal device
+// enables turned off, so the typed line is echoed and editable
+struct CookedStdin;
+
+impl Read for CookedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _ = crossterm_terminal::disable_raw_mode();
+        let read = std::io::stdin().read(buf);
+        let _ = crossterm_terminal::enable_raw_mode();
+        read
+    }
+}
+
+// CrlfStdout writes to stdout, in raw mode "\n" only moves the
+// cursor down so it's written as "\r\n"
+struct CrlfStdout;
+
+impl Write for CrlfStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut stdout = stdout();
+        for line in buf.split_inclusive(|byte| *byte == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(line) => {
+                    stdout.write_all(line)?;
+                    stdout.write_all(b"\r\n")?;
+                }
+                None => stdout.write_all(line)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        stdout().flush()
+    }
 }
@@ -0,0 +1,165 @@
+use std::{fmt, ops::Range};
+
+use super::AsmError;
+
+// Diagnostic is an assembler error located in the source, Display
+// renders it with the source line and a caret under the offending token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    // 1-based, `len` characters from `column` are underlined
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    pub message: String,
+    // the source line without its line break
+    pub source: String,
+    // shown under the source, as the text a macro expanded to
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    // new places the diagnostic on the `span` bytes of `source`
+    pub fn new(file: &str, line: usize, source: &str, span: Range<usize>, message: String) -> Self {
+        Self {
+            file: file.to_string(),
+            line,
+            column: source[..span.start].chars().count() + 1,
+            len: source[span].chars().count(),
+            message,
+            source: source.to_string(),
+            note: None,
+        }
+    }
+
+    // from_expansion locates an error of the line `expanded` from the
+    // macro invoked in `source`, on the token the invocation passed to it
+    // or else on the macro name
+    pub fn from_expansion(
+        file: &str,
+        line: usize,
+        source: &str,
+        name: &str,
+        expanded: &str,
+        err: &AsmError,
+    ) -> Self {
+        let expanded = expanded.trim();
+        let inner = Self::from_error(file, line, expanded, err);
+        let token: String = expanded.chars().skip(inner.column - 1).take(inner.len).collect();
+        let span = Some(token)
+            .filter(|token| !token.is_empty())
+            .and_then(|token| find_token(source, &token))
+            .unwrap_or_else(|| mnemonic_span(source));
+
+        Self {
+            note: Some(format!("in this expansion of `{}`: {}", name, expanded)),
+            ..Self::new(file, line, source, span, inner.message)
+        }
+    }
+
+    // from_error points at the token the error is about, the whole
+    // operands when the error doesn't name one
+    pub fn from_error(file: &str, line: usize, source: &str, err: &AsmError) -> Self {
+        let mnemonic = mnemonic_span(source);
+        let (span, message) = match err {
+            AsmError::InvalidRegister(token)
+            | AsmError::InvalidImmediate(token)
+            | AsmError::UnresolvedLabel(token)
+            | AsmError::ForwardReference(token)
+            | AsmError::DuplicateMacro(token)
+            | AsmError::IncludeNotFound(token)
+            | AsmError::IncludeCycle(token)
+            | AsmError::IncludeFailed { name: token, .. }
+            | AsmError::OutOfRange { token, .. } => (
+                find_token(source, token).unwrap_or_else(|| operands_span(source)),
+                err.to_string(),
+            ),
+            AsmError::InvalidInstruction => {
+                let kind = if source[mnemonic.clone()].starts_with('.') {
+                    "directive"
+                } else {
+                    "instruction"
+                };
+                (
+                    mnemonic.clone(),
+                    format!("unknown {} `{}`", kind, &source[mnemonic]),
+                )
+            }
+            AsmError::MisalignedInstruction(_)
+            | AsmError::UnexpectedData
+            | AsmError::ImageTooLarge
+            | AsmError::UnterminatedMacro
+            | AsmError::UnmatchedEndm
+            | AsmError::MacroArguments { .. }
+            | AsmError::MacroTooDeep(_) => (mnemonic, err.to_string()),
+
+            // the name of a label is its mnemonic
+            AsmError::AlreadyDefined { name, .. } => {
+                let start = source.find(name.as_str()).unwrap_or_default();
+                (start..start + name.len(), err.to_string())
+            }
+            AsmError::InvalidOperands => (
+                operands_span(source),
+                format!("invalid operands for `{}`", source[mnemonic].to_uppercase()),
+            ),
+            AsmError::InvalidFormat => (operands_span(source), err.to_string()),
+        };
+
+        Self::new(file, line, source, span, message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.line.to_string().len();
+        // the tabs are kept so the caret lines up with the source
+        let padding: String = self
+            .source
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{:width$}--> {}:{}:{}", "", self.file, self.line, self.column)?;
+        writeln!(f, "{:width$} |", "")?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+        write!(f, "{:width$} | {}{}", "", padding, "^".repeat(self.len.max(1)))?;
+        if let Some(note) = &self.note {
+            write!(f, "\n{:width$} = note: {}", "", note)?;
+        }
+        Ok(())
+    }
+}
+
+fn mnemonic_span(source: &str) -> Range<usize> {
+    let start = source.len() - source.trim_start().len();
+    let len = source[start..]
+        .find(char::is_whitespace)
+        .unwrap_or(source.len() - start);
+    start..start + len
+}
+
+// operands_span covers everything after the mnemonic, an empty
+// span right after it when there are no operands
+fn operands_span(source: &str) -> Range<usize> {
+    let end = source.trim_end().len();
+    let mnemonic_end = mnemonic_span(source).end.min(end);
+    let start = end - source[mnemonic_end..end].trim_start().len();
+    start..end
+}
+
+// find_token looks for `token` as a whole word among the operands
+fn find_token(source: &str, token: &str) -> Option<Range<usize>> {
+    let from = mnemonic_span(source).end;
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+    source[from..]
+        .match_indices(token)
+        .map(|(idx, _)| from + idx)
+        .find(|&start| {
+            let end = start + token.len();
+            !source[..start].ends_with(is_word) && !source[end..].starts_with(is_word)
+        })
+        .map(|start| start..start + token.len())
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use super::AsmError;
+
+// eval computes a constant expression, the symbols are the labels and
+// the .equ/.set constants known so far
+//
+//     12  0x1F  0b101  'A' '\n'   numbers and characters
+//     - ~                         unary minus and not
+//     * /                         then, from the tightest binding
+//     + -
+//     << >>
+//     &
+//     |
+//
+// an undefined symbol is an UnresolvedLabel, any other problem an
+// InvalidImmediate holding `expr`
+pub(crate) fn eval(expr: &str, symbols: &HashMap<String, i32>) -> Result<i32, AsmError> {
+    let mut parser = Parser {
+        expr,
+        pos: 0,
+        symbols,
+    };
+
+    let value = parser.or()?;
+    if parser.peek().is_some() {
+        return Err(parser.invalid());
+    }
+
+    Ok(value)
+}
+
+// has_symbols tells if `expr` refers to a label or a constant
+pub(crate) fn has_symbols(expr: &str) -> bool {
+    let mut in_char = false;
+    let mut prev = ' ';
+    for c in expr.chars() {
+        if c == '\'' && prev != '\\' {
+            in_char = !in_char;
+        } else if !in_char && is_symbol_start(c) && !is_symbol_char(prev) {
+            return true;
+        }
+        prev = c;
+    }
+
+    false
+}
+
+// is_symbol tells if `name` can be used as a symbol
+pub(crate) fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_symbol_start) && chars.all(is_symbol_char)
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+struct Parser<'a> {
+    expr: &'a str,
+    pos: usize,
+    symbols: &'a HashMap<String, i32>,
+}
+
+impl<'a> Parser<'a> {
+    fn invalid(&self) -> AsmError {
+        AsmError::InvalidImmediate(self.expr.to_string())
+    }
+
+    // peek skips the spaces and returns the next character
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.expr[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        self.expr[self.pos..].chars().next()
+    }
+
+    // eat consumes `op` when it comes next
+    fn eat(&mut self, op: &str) -> bool {
+        self.peek();
+        if self.expr[self.pos..].starts_with(op) {
+            self.pos += op.len();
+            return true;
+        }
+
+        false
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[&str],
+        next: fn(&mut Self) -> Result<i32, AsmError>,
+    ) -> Result<i32, AsmError> {
+        let mut value = next(self)?;
+        'outer: loop {
+            for op in ops {
+                if self.eat(op) {
+                    let rhs = next(self)?;
+                    value = self.apply(op, value, rhs)?;
+                    continue 'outer;
+                }
+            }
+
+            return Ok(value);
+        }
+    }
+
+    fn apply(&self, op: &str, lhs: i32, rhs: i32) -> Result<i32, AsmError> {
+        let value = match op {
+            "|" => Some(lhs | rhs),
+            "&" => Some(lhs & rhs),
+            "<<" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+            ">>" => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+            "+" => lhs.checked_add(rhs),
+            "-" => lhs.checked_sub(rhs),
+            "*" => lhs.checked_mul(rhs),
+            "/" => lhs.checked_div(rhs),
+            _ => unreachable!(),
+        };
+
+        value.ok_or_else(|| self.invalid())
+    }
+
+    fn or(&mut self) -> Result<i32, AsmError> {
+        self.binary(&["|"], Self::and)
+    }
+
+    fn and(&mut self) -> Result<i32, AsmError> {
+        self.binary(&["&"], Self::shift)
+    }
+
+    fn shift(&mut self) -> Result<i32, AsmError> {
+        self.binary(&["<<", ">>"], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<i32, AsmError> {
+        self.binary(&["+", "-"], Self::product)
+    }
+
+    fn product(&mut self) -> Result<i32, AsmError> {
+        self.binary(&["*", "/"], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<i32, AsmError> {
+        if self.eat("-") {
+            return self.unary()?.checked_neg().ok_or_else(|| self.invalid());
+        }
+        if self.eat("~") {
+            return Ok(!self.unary()?);
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i32, AsmError> {
+        if self.eat("(") {
+            let value = self.or()?;
+            if !self.eat(")") {
+                return Err(self.invalid());
+            }
+            return Ok(value);
+        }
+
+        match self.peek() {
+            Some('\'') => self.character(),
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if is_symbol_start(c) => self.symbol(),
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn take_while(&mut self, pred: fn(char) -> bool) -> &'a str {
+        let rest = &self.expr[self.pos..];
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn number(&mut self) -> Result<i32, AsmError> {
+        let token = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        let (digits, radix) = if let Some(hex) = token.strip_prefix("0x") {
+            (hex, 16)
+        } else if let Some(bin) = token.strip_prefix("0b") {
+            (bin, 2)
+        } else {
+            (token, 10)
+        };
+
+        let digits = digits.replace('_', "");
+        i32::from_str_radix(&digits, radix)
+            .ok()
+            .filter(|_| !digits.is_empty())
+            .ok_or_else(|| self.invalid())
+    }
+
+    // 'A', with the \n, \r, \t, \0, \\ and \' escapes
+    fn character(&mut self) -> Result<i32, AsmError> {
+        let mut chars = self.expr[self.pos + 1..].chars();
+        let c = match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '\'')) => c,
+                _ => return Err(self.invalid()),
+            },
+            Some('\'') | None => return Err(self.invalid()),
+            Some(c) => c,
+        };
+
+        if chars.next() != Some('\'') {
+            return Err(self.invalid());
+        }
+
+        let rest = chars.as_str().len();
+        self.pos = self.expr.len() - rest;
+        Ok(c as i32)
+    }
+
+    fn symbol(&mut self) -> Result<i32, AsmError> {
+        let name = self.take_while(is_symbol_char);
+        self.symbols
+            .get(name)
+            .copied()
+            .ok_or_else(|| AsmError::UnresolvedLabel(name.to_string()))
+    }
+}
@@ -2,14 +2,7 @@
 macro_rules! rv16asm {
     () => { Vec::<u16>::new() };
 
-    ($inst:expr) => {{
-        use super::{parse_assembly, encode_instructions};
-        let instructions = parse_assembly($instr)
-            .expect(&format!("failed to parse asm: {}", $instr));
-
-        encode_instructions(&instructions)
-    }};
-        
+    // a single instruction, with or without a trailing comma, is a list too
     ($($inst:expr),* $(,)?) => {{
         use $crate::asm::{parse_assembly, encode_instructions};
         let code = concat!($($inst, "\n"),*);
@@ -1,22 +1,128 @@
-use crate::machine::{ArithmeticOp, CompareOp, Instruction, Register};
+use crate::machine::{ArithmeticOp, BitwiseOp, CompareOp, Instruction, Register};
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{HashMap, HashSet},
     env::args,
-    fmt::format,
+    fmt::{self, format},
     hash::Hash,
+    path::PathBuf,
     str::FromStr,
 };
 
+pub mod diagnostic;
+mod expr;
 pub mod macros;
+mod preprocess;
 
-#[derive(Debug)]
+use diagnostic::Diagnostic;
+use preprocess::{Line, preprocess, split_args};
+
+// the errors hold the offending token, so diagnostics can point at it
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AsmError {
-    InvalidRegister,
+    InvalidRegister(String),
     InvalidOperands,
     InvalidInstruction,
     InvalidFormat,
-    InvalidImmediate,
+    InvalidImmediate(String),
     UnresolvedLabel(String),
+    // instructions must start at even addresses
+    MisalignedInstruction(u16),
+    // a data directive where only instructions are expected
+    UnexpectedData,
+    // the program takes the whole 64KiB address space or more
+    ImageTooLarge,
+    // the value of `token` doesn't fit the field, `min..=max`
+    OutOfRange {
+        token: String,
+        value: i32,
+        min: i32,
+        max: i32,
+    },
+    // a directive sizing the image used a symbol defined after it
+    ForwardReference(String),
+    // a label or constant defined twice, `kind` tells which
+    AlreadyDefined {
+        kind: &'static str,
+        name: String,
+    },
+    // a .macro without its .endm
+    UnterminatedMacro,
+    // a .endm without its .macro
+    UnmatchedEndm,
+    DuplicateMacro(String),
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    // the macro invocations nest too deep, likely a recursive macro
+    MacroTooDeep(String),
+    IncludeNotFound(String),
+    // a file including itself, through other files or not
+    IncludeCycle(String),
+    IncludeFailed {
+        name: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::InvalidRegister(reg) => write!(f, "invalid register `{}`", reg),
+            AsmError::InvalidOperands => write!(f, "invalid operands"),
+            AsmError::InvalidInstruction => write!(f, "unknown instruction"),
+            AsmError::InvalidFormat => write!(f, "invalid format"),
+            AsmError::InvalidImmediate(imm) => write!(f, "invalid immediate `{}`", imm),
+            AsmError::UnresolvedLabel(label) => write!(f, "undefined symbol `{}`", label),
+            AsmError::MisalignedInstruction(addr) => write!(
+                f,
+                "instruction at the odd address {:#06x}, place `.align #2` before it",
+                addr
+            ),
+            AsmError::UnexpectedData => {
+                write!(f, "data directives need an image, assemble with `asm::assemble`")
+            }
+            AsmError::ImageTooLarge => write!(f, "the program does not fit in 64KiB"),
+            AsmError::OutOfRange { token, value, min, max } => {
+                if token.trim_start_matches('#') == value.to_string() {
+                    write!(f, "`{}` is out of range, expected {} to {}", token, min, max)
+                } else {
+                    write!(
+                        f,
+                        "`{}` is {}, out of range, expected {} to {}",
+                        token, value, min, max
+                    )
+                }
+            }
+            AsmError::ForwardReference(symbol) => {
+                write!(f, "`{}` must be defined before this line", symbol)
+            }
+            AsmError::AlreadyDefined { kind, name } => {
+                write!(f, "{} `{}` is already defined", kind, name)
+            }
+            AsmError::UnterminatedMacro => write!(f, "`.macro` without a matching `.endm`"),
+            AsmError::UnmatchedEndm => write!(f, "`.endm` without a matching `.macro`"),
+            AsmError::DuplicateMacro(name) => write!(f, "macro `{}` is already defined", name),
+            AsmError::MacroArguments { name, expected, found } => write!(
+                f,
+                "macro `{}` takes {} arguments but {} were given",
+                name, expected, found
+            ),
+            AsmError::MacroTooDeep(name) => {
+                write!(f, "macro `{}` nests too deep, does it invoke itself?", name)
+            }
+            AsmError::IncludeNotFound(name) => write!(
+                f,
+                "`{}` not found next to the file or in the include paths",
+                name
+            ),
+            AsmError::IncludeCycle(name) => {
+                write!(f, "`{}` is already being included, the includes make a cycle", name)
+            }
+            AsmError::IncludeFailed { name, reason } => write!(f, "reading `{}`: {}", name, reason),
+        }
+    }
 }
 
 impl FromStr for Register {
@@ -32,7 +138,7 @@ impl FromStr for Register {
             "BP" => Ok(Register::BP),
             "SP" => Ok(Register::SP),
             "FLAGS" => Ok(Register::FLAGS),
-            _ => Err(AsmError::InvalidRegister),
+            _ => Err(AsmError::InvalidRegister(s.to_string())),
         }
     }
 }
@@ -52,25 +158,29 @@ impl ToString for Instruction {
             
             Instruction::MovShift(reg, shift_amt, is_left, imm) => {
                 if *is_left {
-                    format!("MSL {}, [#{} #{}]", reg.to_string(), shift_amt, imm)
+                    format!("MSL {}, [#{} #{}]", reg.to_string(), imm, shift_amt)
                 } else {
-                    format!("MSR {}, [#{} #{}]", reg.to_string(), shift_amt, imm)
+                    format!("MSR {}, [#{} #{}]", reg.to_string(), imm, shift_amt)
                 }
             }
             Instruction::Cpy(src_reg, dst_reg) => {
                 format!("CPY {}, {}", src_reg.to_string(), dst_reg.to_string())
             }
             Instruction::Arith(reg, opt_reg, opt_imm, op) => {
+                let is_signed = is_signed_arith(*op);
                 let op = match op {
                     ArithmeticOp::Add => "ADD",
                     ArithmeticOp::Sub => "SUB",
                     ArithmeticOp::Mul => "MUL",
                     ArithmeticOp::Div => "DIV",
+                    ArithmeticOp::SignedDiv => "SDIV",
+                    ArithmeticOp::SignedMod => "SMOD",
                     _ => unreachable!(),
                 };
 
                 let operand = match (opt_reg, opt_imm) {
                     (Some(reg), None) => reg.to_string(),
+                    (None, Some(imm)) if is_signed => format!("#{}", *imm as i16),
                     (None, Some(imm)) => format!("#{}", imm.to_string()),
                     _ => format!("undefined"),
                 };
@@ -86,6 +196,8 @@ impl ToString for Instruction {
                     ArithmeticOp::Mod => "MODR",
                     ArithmeticOp::Exp => "EXPR",
                     ArithmeticOp::Sqrt => "SQRTR",
+                    ArithmeticOp::Adc => "ADCR",
+                    ArithmeticOp::SignedDiv | ArithmeticOp::SignedMod => unreachable!(),
                 };
 
                 format!("{} {}, {}, {}", op.to_string(), dst_reg.to_string(), fst_reg.to_string(), snd_reg.to_string())
@@ -141,6 +253,7 @@ impl ToString for Instruction {
                 format!("CJP {}", src)
             }
             Instruction::Cmp(reg, opt_reg, opt_imm, op) => {
+                let is_signed = op.is_signed();
                 let op = match op {
                     CompareOp::Eq => "EQ",
                     CompareOp::NotEq => "NEQ",
@@ -148,10 +261,15 @@ impl ToString for Instruction {
                     CompareOp::LessEq => "LTE",
                     CompareOp::Greater => "GT",
                     CompareOp::GreaterEq => "GTE",
+                    CompareOp::SignedLess => "SLT",
+                    CompareOp::SignedLessEq => "SLTE",
+                    CompareOp::SignedGreater => "SGT",
+                    CompareOp::SignedGreaterEq => "SGTE",
                 };
 
                 let operand = match (opt_reg, opt_imm) {
                     (Some(reg), None) => reg.to_string(),
+                    (None, Some(imm)) if is_signed => format!("#{}", *imm as i16),
                     (None, Some(imm)) => format!("#{}", imm.to_string()),
                     _ => format!("undefined"),
                 };
@@ -160,10 +278,58 @@ impl ToString for Instruction {
             }
             Instruction::CallRet(is_ret, addr) => {
                 if *is_ret {
-                    return "RET".to_string();
+                    return "LRET".to_string();
                 }
 
-                format!("CALL #{}", addr.to_string())
+                format!("LCALL #{}", addr.to_string())
+            }
+            Instruction::Call(opt_reg, opt_imm) => {
+                let src = match (opt_reg, opt_imm) {
+                    (Some(reg), None) => reg.to_string(),
+                    (None, Some(imm)) => format!("#{}", imm),
+                    _ => "undefined".to_string(),
+                };
+
+                format!("CALL {}", src)
+            }
+            Instruction::Ret => "RET".to_string(),
+            Instruction::Bitwise(reg, opt_reg, opt_imm, op) => {
+                let op = match op {
+                    BitwiseOp::And => "AND",
+                    BitwiseOp::Or => "OR",
+                    BitwiseOp::Xor => "XOR",
+                    BitwiseOp::Not => "NOT",
+                    BitwiseOp::ShiftLeft => "SHL",
+                    BitwiseOp::ShiftRight => "SHR",
+                    BitwiseOp::ShiftArith => "SAR",
+                };
+
+                let operand = match (opt_reg, opt_imm) {
+                    (Some(reg), None) => reg.to_string(),
+                    (None, Some(imm)) => format!("#{}", imm),
+                    _ => "undefined".to_string(),
+                };
+
+                format!("{} {}, {}", op, reg.to_string(), operand)
+            }
+            Instruction::Rti => "RTI".to_string(),
+            Instruction::Push(reg) => format!("PUSH {}", reg.to_string()),
+            Instruction::Pop(reg) => format!("POP {}", reg.to_string()),
+            Instruction::Enter(size) => format!("ENTER #{}", size),
+            Instruction::Leave => "LEAVE".to_string(),
+            Instruction::Breakpoint(0) => "DBG".to_string(),
+            Instruction::Breakpoint(tag) => format!("DBG #{}", tag),
+            Instruction::Syscall(number) => format!("SYSCALL #{}", number),
+            Instruction::Halt(0) => "HALT".to_string(),
+            Instruction::Halt(code) => format!("HALT #{}", code),
+            Instruction::LdbSigned(reg, rhs_reg, shift) => {
+                let rhs = if *shift == 0 {
+                    rhs_reg.to_string()
+                } else {
+                    format!("[{} #{}]", rhs_reg.to_string(), shift)
+                };
+
+                format!("LDBS {}, {}", reg.to_string(), rhs)
             }
             _=>{
                 unreachable!()
@@ -202,6 +368,13 @@ pub fn encode_instruction(inst: &Instruction) -> u16 {
             let dst_reg = (*dst as u16) & 0b111;
             (dst_reg << 7) | (src_reg << 4) | 0b1001
         }
+        Instruction::Arith(dst_reg, opt_src_reg, opt_imm, op) if is_signed_arith(*op) => {
+            let op = match op {
+                ArithmeticOp::SignedDiv => 0b100,
+                _ => 0b101,
+            };
+            encode_signed(*dst_reg, *opt_src_reg, *opt_imm, op)
+        }
         Instruction::Arith(dst_reg, opt_src_reg, opt_imm, op) => {
             let reg_code = (*dst_reg as u16) & 0b111;
             let op = match op {
@@ -230,6 +403,8 @@ pub fn encode_instruction(inst: &Instruction) -> u16 {
                 ArithmeticOp::Mod => 0b100,
                 ArithmeticOp::Exp => 0b101,
                 ArithmeticOp::Sqrt => 0b110,
+                ArithmeticOp::Adc => 0b111,
+                ArithmeticOp::SignedDiv | ArithmeticOp::SignedMod => unreachable!(),
             }; 
             
             let fst_code: u16 = (*fst_reg as u16) & 0b111;
@@ -274,6 +449,15 @@ pub fn encode_instruction(inst: &Instruction) -> u16 {
             };
             (value << 5) | (mode << 4) | 0b0101
         }
+        Instruction::Cmp(reg, opt_reg, opt_imm, cmp_op) if cmp_op.is_signed() => {
+            let op = match cmp_op {
+                CompareOp::SignedLess => 0b000,
+                CompareOp::SignedLessEq => 0b001,
+                CompareOp::SignedGreater => 0b010,
+                _ => 0b011,
+            };
+            encode_signed(*reg, *opt_reg, *opt_imm, op)
+        }
         Instruction::Cmp(reg, opt_reg, opt_imm, cmp_op) => {
             let r0 = (*reg as u16) & 0b111;
             let cmp = (*cmp_op as u16) & 0b111;
@@ -290,9 +474,57 @@ pub fn encode_instruction(inst: &Instruction) -> u16 {
 
             (imm << 5) | ((flag & 0b1) << 4) | 0b1011
         },
+        Instruction::Call(opt_reg, opt_imm) => {
+            let (mode, value) = match (opt_reg, opt_imm) {
+                (Some(reg), None) => (0b1, (*reg as u16) & 0b111),
+                (None, Some(imm)) => (0b0, *imm & 0b11111111111),
+                _ => unreachable!(),
+            };
+            (value << 5) | (mode << 4) | 0b1100
+        }
+        Instruction::Ret => (0b0101 << 4) | 0b1111,
+        Instruction::Bitwise(reg, opt_reg, opt_imm, op) => {
+            let r0 = (*reg as u16) & 0b111;
+            let op = (*op as u16) & 0b111;
+            let (mode, value) = match (opt_reg, opt_imm) {
+                (Some(reg), None) => (0b1, (*reg as u16) & 0b111),
+                (None, Some(imm)) => (0b0, *imm & 0b11111),
+                _ => unreachable!(),
+            };
+            (value << 11) | (mode << 10) | (op << 7) | (r0 << 4) | 0b1101
+        }
+        Instruction::Rti => 0b0000_1111,
+        Instruction::Push(reg) => ((*reg as u16) << 8) | (0b0001 << 4) | 0b1111,
+        Instruction::Pop(reg) => ((*reg as u16) << 8) | (0b0010 << 4) | 0b1111,
+        Instruction::Enter(size) => ((*size as u16) << 8) | (0b0011 << 4) | 0b1111,
+        Instruction::Leave => (0b0100 << 4) | 0b1111,
+        Instruction::Breakpoint(tag) => ((*tag as u16) << 8) | (0b0110 << 4) | 0b1111,
+        Instruction::Syscall(number) => ((*number as u16) << 8) | (0b0111 << 4) | 0b1111,
+        Instruction::Halt(code) => ((*code as u16) << 8) | (0b1000 << 4) | 0b1111,
+        Instruction::LdbSigned(r0, addr_reg, shift) => {
+            let r0_code = (*r0 as u16) & 0b111;
+            let addr_reg = (*addr_reg as u16) & 0b111;
+            let shift = (*shift as u16) & 0b111;
+            (shift << 13) | (addr_reg << 10) | (0b110 << 7) | (r0_code << 4) | 0b1110
+        }
     }
 }
 
+fn is_signed_arith(op: ArithmeticOp) -> bool {
+    matches!(op, ArithmeticOp::SignedDiv | ArithmeticOp::SignedMod)
+}
+
+// signed group: 1110 | reg(3) | op(3) | mode(1) | [mode == 1]reg(3), [mode == 0]imm(5)
+fn encode_signed(reg: Register, opt_reg: Option<Register>, opt_imm: Option<u16>, op: u16) -> u16 {
+    let r0 = (reg as u16) & 0b111;
+    let (mode, value) = match (opt_reg, opt_imm) {
+        (Some(reg), None) => (0b1, (reg as u16) & 0b111),
+        (None, Some(imm)) => (0b0, imm & 0b11111),
+        _ => unreachable!(),
+    };
+    (value << 11) | (mode << 10) | (op << 7) | (r0 << 4) | 0b1110
+}
+
 pub fn parse_assembly(code: &str) -> Result<Vec<Instruction>, AsmError> {
     let mut instructions = Vec::new();
 
@@ -306,95 +538,427 @@ pub fn parse_assembly(code: &str) -> Result<Vec<Instruction>, AsmError> {
             continue;
         }
 
-        let inst = parse_assembly_line(line, &empty_labels)?;
-        instructions.push(inst);
+        instructions.extend(expand_assembly_line(line, &empty_labels)?);
     }
 
     Ok(instructions)
 }
 
-// read the contents of the assembly file
-pub fn resolve_and_parse_assembly(code: &str) -> Result<Vec<Instruction>, AsmError> {
-    // labels should hold the address from the right next instruction to it
-    let mut labels: HashMap<String, u16> = HashMap::new();
-    let mut unresolved: HashMap<String, Vec<(usize, String)>> = HashMap::new();
-    let mut instructions: Vec<Instruction> = vec![];
+// read the contents of the assembly file, data directives are
+// rejected since the result only holds instructions
+pub fn resolve_and_parse_assembly(code: &str) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
+    let items = assemble_items(&[("<input>", code)], &[], false)?;
+    Ok(items
+        .into_iter()
+        .flat_map(|item| match item {
+            Item::Code(insts) => insts,
+            Item::Data(_) => unreachable!("data directives are rejected"),
+        })
+        .collect())
+}
 
-    // considering  we start at addr 0 and we increase 2 (given
-    // that each instruction is 2 bytes long) we might be fine.
-    let mut curr_inst_addr = 0;
-    let mut line_number = 0;
+// assemble returns the memory image of `code`, starting at address 0.
+// Instructions and data directives are laid out in source order, the
+// image is padded to a whole amount of words
+pub fn assemble(file: &str, code: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    assemble_sources(&[(file, code)], &[]).map(|image| image.bytes)
+}
 
-    for line in code.lines().into_iter() {
-        line_number += 1;
-        let line = line.trim();
+// Image is an assembled program: the bytes loaded at address 0
+// and the (address, length) spans holding instructions, only
+// those are loaded read-only so the data can be written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub bytes: Vec<u8>,
+    pub code: Vec<(u16, u16)>,
+}
+
+// A `.bin` file holds an image, every value is little endian:
+//
+//     magic "RV16PROG" | version (u16) | spans (u16)
+//     | (addr u16, len u16)... | image bytes
+pub const IMAGE_MAGIC: &[u8; 8] = b"RV16PROG";
+pub const IMAGE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    // the file doesn't start with IMAGE_MAGIC, as the raw
+    // instruction words written by older versions of asm
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    // the file ended before the code spans were read
+    Truncated,
+    // the image isn't a whole amount of words
+    OddLength,
+    // a code span goes past the end of the image
+    SpanOutOfImage { addr: u16, len: u16 },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::InvalidMagic => write!(f, "not an rv16 image, assemble it again with asm"),
+            ImageError::UnsupportedVersion(version) => {
+                write!(f, "unsupported image version {}", version)
+            }
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::OddLength => write!(f, "image is not a whole amount of words"),
+            ImageError::SpanOutOfImage { addr, len } => {
+                write!(f, "code at {:#06x} ({} bytes) is past the image", addr, len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl Image {
+    // to_bin returns the contents of a `.bin` file
+    pub fn to_bin(&self) -> Vec<u8> {
+        let mut bin = IMAGE_MAGIC.to_vec();
+        bin.extend(IMAGE_VERSION.to_le_bytes());
+        bin.extend((self.code.len() as u16).to_le_bytes());
+        for (addr, len) in self.code.iter() {
+            bin.extend(addr.to_le_bytes());
+            bin.extend(len.to_le_bytes());
+        }
+        bin.extend(&self.bytes);
+        bin
+    }
+
+    // from_bin reads a `.bin` file written by `to_bin`
+    pub fn from_bin(bin: &[u8]) -> Result<Image, ImageError> {
+        let rest = bin
+            .strip_prefix(&IMAGE_MAGIC[..])
+            .ok_or(ImageError::InvalidMagic)?;
+
+        let mut words = rest.chunks(2).map(|word| match word {
+            [lo, hi] => Ok(u16::from_le_bytes([*lo, *hi])),
+            _ => Err(ImageError::Truncated),
+        });
+        let mut word = || words.next().unwrap_or(Err(ImageError::Truncated));
+
+        let version = word()?;
+        if version != IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let spans = word()?;
+        let mut code = vec![];
+        for _ in 0..spans {
+            code.push((word()?, word()?));
+        }
+
+        let header = 4 + spans as usize * 4;
+        let bytes = rest[header..].to_vec();
+        if !bytes.len().is_multiple_of(2) {
+            return Err(ImageError::OddLength);
+        }
+        if let Some((addr, len)) = code
+            .iter()
+            .find(|(addr, len)| *addr as usize + *len as usize > bytes.len())
+        {
+            return Err(ImageError::SpanOutOfImage { addr: *addr, len: *len });
+        }
+
+        Ok(Image { bytes, code })
+    }
+}
+
+// assemble_sources assembles the (file, code) `sources` into one image,
+// one after the other. Their labels, constants and macros are shared, so
+// a file can call the routines of the next ones. `.include` looks for
+// the file next to the including one, then in the `include_paths`
+pub fn assemble_sources(
+    sources: &[(&str, &str)],
+    include_paths: &[PathBuf],
+) -> Result<Image, Vec<Diagnostic>> {
+    let items = assemble_items(sources, include_paths, true)?;
+
+    let mut image = Image {
+        bytes: vec![],
+        code: vec![],
+    };
+    for item in items {
+        match item {
+            Item::Code(insts) => {
+                let addr = image.bytes.len() as u16;
+                let len = insts.len() as u16 * 2;
+                // the instructions following other ones extend their span
+                match image.code.last_mut() {
+                    Some((start, span)) if *start + *span == addr => *span += len,
+                    _ if len > 0 => image.code.push((addr, len)),
+                    _ => {}
+                }
+
+                for inst in insts {
+                    image.bytes.extend(encode_instruction(&inst).to_le_bytes());
+                }
+            }
+            Item::Data(bytes) => image.bytes.extend(bytes),
+        }
+    }
+
+    if !image.bytes.len().is_multiple_of(2) {
+        image.bytes.push(0);
+    }
+    Ok(image)
+}
+
+// Item is what a source line assembles to
+enum Item {
+    Code(Vec<Instruction>),
+    Data(Vec<u8>),
+}
+
+impl Item {
+    fn size(&self) -> usize {
+        match self {
+            Item::Code(insts) => insts.len() * 2,
+            Item::Data(bytes) => bytes.len(),
+        }
+    }
+}
+
+// assemble_items resolves the labels and assembles the sources, it goes on
+// after an error so every problem is reported, sorted by file and line
+fn assemble_items(
+    sources: &[(&str, &str)],
+    include_paths: &[PathBuf],
+    allow_data: bool,
+) -> Result<Vec<Item>, Vec<Diagnostic>> {
+    let (lines, files, mut diagnostics) = preprocess(sources, include_paths);
+
+    // labels hold the address of the item right after them, the
+    // .equ/.set constants share their names
+    let mut labels: HashMap<String, i32> = HashMap::new();
+    // the .set constants, the only symbols that can be defined again
+    let mut variables: HashSet<&str> = HashSet::new();
+    // the lines waiting for a label: item index, address and line
+    let mut unresolved: HashMap<String, Vec<(usize, u16, &Line)>> = HashMap::new();
+    let mut items: Vec<Item> = vec![];
+
+    // the location counter, in bytes
+    let mut addr: usize = 0;
+
+    for source in &lines {
+        let line = source.text.trim();
 
         if line.len() == 0 || line.starts_with(";") {
-            // don't need to increase addr
             continue;
         }
 
-        // found a label
-        if line.ends_with(":") {
-            let label = line.trim_end_matches(":");
-
-            // need to figure out how to calculate
-            // the instruction address offset
-            labels.insert(label.to_string(), curr_inst_addr);
+        // found a label or a constant
+        let definition = match line.strip_suffix(":") {
+            Some(label) => Some(Ok((label, addr as i32, false))),
+            None => parse_constant(line, &labels),
+        };
 
-            if let Some(unresolved_label_loc) = unresolved.get(label) {
-                for (inst_idx, inst_line) in unresolved_label_loc.iter() {
-                    match parse_assembly_line(inst_line.as_ref(), &labels) {
-                        Ok(inst) => instructions[*inst_idx] = inst,
-                        Err(err) => eprintln!("while resolving label: {:?}", err),
-                    }
+        if let Some(definition) = definition {
+            let (name, value, is_set) = match definition {
+                Ok(definition) => definition,
+                Err(err) => {
+                    let err = match err {
+                        AsmError::UnresolvedLabel(symbol) => AsmError::ForwardReference(symbol),
+                        err => err,
+                    };
+                    diagnostics.push(source.diagnostic(&err));
+                    continue;
                 }
+            };
 
-                unresolved.remove(label);
+            if labels.contains_key(name) && !(is_set && variables.contains(name)) {
+                let kind = if line.ends_with(':') { "label" } else { "symbol" };
+                let err = AsmError::AlreadyDefined {
+                    kind,
+                    name: name.to_string(),
+                };
+                diagnostics.push(source.diagnostic(&err));
+                continue;
+            }
+
+            if is_set {
+                variables.insert(name);
+            }
+            labels.insert(name.to_string(), value);
+
+            for (item_idx, item_addr, use_line) in unresolved.remove(name).unwrap_or_default() {
+                match assemble_line(use_line.text.trim(), &labels, item_addr) {
+                    Ok(item) => items[item_idx] = item,
+                    // the line uses another symbol still to come
+                    Err(AsmError::UnresolvedLabel(next)) => unresolved
+                        .entry(next)
+                        .or_default()
+                        .push((item_idx, item_addr, use_line)),
+                    Err(err) => diagnostics.push(use_line.diagnostic(&err)),
+                }
             }
 
             continue;
         }
 
-        match parse_assembly_line(line.as_ref(), &labels) {
-            Ok(inst) => instructions.push(inst),
+        let is_data = line.starts_with('.');
+        let result = if is_data && !allow_data {
+            Err(AsmError::UnexpectedData)
+        } else if !is_data && !addr.is_multiple_of(2) {
+            Err(AsmError::MisalignedInstruction(addr as u16))
+        } else {
+            assemble_line(line, &labels, addr as u16)
+        };
+
+        let item = match result {
+            Ok(item) => item,
+            // the size of the other directives depends on their values
+            Err(AsmError::UnresolvedLabel(symbol)) if is_data && !waits_for_symbols(line) => {
+                let err = AsmError::ForwardReference(symbol);
+                diagnostics.push(source.diagnostic(&err));
+                continue;
+            }
             Err(AsmError::UnresolvedLabel(label)) => {
-                instructions.push(Instruction::Noop);
-
-                match unresolved.entry(label) {
-                    Entry::Occupied(mut entry) => {
-                        entry
-                            .get_mut()
-                            .push((instructions.len() - 1, line.to_string()));
-                    }
-                    Entry::Vacant(entry) => {
-                        entry.insert(vec![(instructions.len() - 1, line.to_string())]);
-                    }
-                };
+                // the line is assembled again once the label is found,
+                // the placeholder keeps the addresses of the next lines
+                unresolved
+                    .entry(label)
+                    .or_default()
+                    .push((items.len(), addr as u16, source));
+                unresolved_item(line)
             }
             Err(err) => {
-                eprintln!("problems at line: {}: {}", line_number, line.to_string());
-                return Err(err)
-            },
+                diagnostics.push(source.diagnostic(&err));
+                continue;
+            }
+        };
+
+        addr += item.size();
+        items.push(item);
+
+        // the image, padded to whole words, must have its length fit
+        // in a u16: it can't take the whole address space
+        if addr.next_multiple_of(2) >= 1 << 16 {
+            diagnostics.push(source.diagnostic(&AsmError::ImageTooLarge));
+            return Err(diagnostics);
         }
+    }
 
-        curr_inst_addr += 2;
+    // the symbols never defined would be left as placeholders
+    for (label, uses) in unresolved {
+        for (_, _, line) in uses {
+            diagnostics.push(line.diagnostic(&AsmError::UnresolvedLabel(label.clone())));
+        }
     }
 
-    Ok(instructions)
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|diagnostic| {
+            let file = files.iter().position(|file| **file == *diagnostic.file);
+            (file.unwrap_or(files.len()), diagnostic.line, diagnostic.column)
+        });
+        return Err(diagnostics);
+    }
+
+    Ok(items)
+}
+
+// parse_constant reads `.equ NAME, value` and `.set NAME, value`, None
+// for the other lines. Only .set constants can be given a new value,
+// the value keeps its sign so it can fill signed fields
+fn parse_constant<'a>(
+    line: &'a str,
+    labels: &HashMap<String, i32>,
+) -> Option<Result<(&'a str, i32, bool), AsmError>> {
+    let (directive, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let is_set = match directive.to_lowercase().as_str() {
+        ".equ" => false,
+        ".set" => true,
+        _ => return None,
+    };
+
+    let constant = match args.split_once(',') {
+        Some((name, value)) if expr::is_symbol(name.trim()) => {
+            let value = value.trim();
+            parse_value(value, labels)
+                .and_then(|word| {
+                    check_range(value, word, i16::MIN as i32, u16::MAX as i32)?;
+                    Ok((name.trim(), word, is_set))
+                })
+        }
+        _ => Err(AsmError::InvalidOperands),
+    };
+    Some(constant)
+}
+
+// waits_for_symbols tells if a data directive keeps its size whatever
+// the value of its symbols, so it can use the ones defined after it
+fn waits_for_symbols(line: &str) -> bool {
+    let name = line.split_whitespace().next().unwrap_or_default();
+    name.eq_ignore_ascii_case(".byte") || name.eq_ignore_ascii_case(".word")
+}
+
+// assemble_line assembles an instruction or a data directive at `addr`
+fn assemble_line(line: &str, labels: &HashMap<String, i32>, addr: u16) -> Result<Item, AsmError> {
+    if line.starts_with('.') {
+        return Ok(Item::Data(parse_directive(line, labels, addr)?));
+    }
+
+    Ok(Item::Code(expand_assembly_line(line, labels)?))
 }
 
 pub fn encode_instructions(instructions: &[Instruction]) -> Vec<u16> {
     instructions.iter().map(encode_instruction).collect()
 }
 
-type ParserFn<'a> = Box<dyn Fn(&[&str]) -> Result<Instruction, AsmError> + 'a>;
+// image_words splits an image from `assemble` into little endian
+// words, as `LinearMemory::write_program` takes them
+pub fn image_words(image: &[u8]) -> Vec<u16> {
+    image
+        .chunks(2)
+        .map(|word| u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]))
+        .collect()
+}
+
+// LI using a symbol always takes LI_LABEL_LEN instructions, the
+// value may only be known after the next lines were placed
+const LI_LABEL_LEN: usize = 3;
+
+// expand_assembly_line assembles a line into the instructions it
+// stands for, pseudo-instructions as LI expand to several of them
+pub fn expand_assembly_line(
+    line: &str,
+    labels: &HashMap<String, i32>,
+) -> Result<Vec<Instruction>, AsmError> {
+    let parts = split_operands(line);
+    match parts.first() {
+        Some(name) if name.eq_ignore_ascii_case("LI") => parse_li(&parts[1..], labels),
+        _ => Ok(vec![parse_assembly_line(line, labels)?]),
+    }
+}
+
+// unresolved_item is the placeholder of a line waiting for a label,
+// as long as the line will be once the label is resolved
+fn unresolved_item(line: &str) -> Item {
+    let line = line.trim();
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    match name {
+        name if name.eq_ignore_ascii_case("LI") => {
+            Item::Code(vec![Instruction::Noop; LI_LABEL_LEN])
+        }
+        // only .byte and .word may wait for a symbol
+        name if name.starts_with('.') => {
+            let size = if name.eq_ignore_ascii_case(".byte") { 1 } else { 2 };
+            Item::Data(vec![0; split_args(args).len() * size])
+        }
+        _ => Item::Code(vec![Instruction::Noop]),
+    }
+}
 
-pub fn parse_assembly_line<'a>(
+type ParserFn = Box<dyn Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError>>;
+
+// parse_assembly_line assembles one instruction, `labels` holds the
+// labels and constants its immediates may use
+pub fn parse_assembly_line(
     line: &str,
-    labels: &'a HashMap<String, u16>,
+    labels: &HashMap<String, i32>,
 ) -> Result<Instruction, AsmError> {
-    let parts: Vec<&str> = line.split_whitespace().collect();
+    let parts = split_operands(line);
     if parts.is_empty() {
         return Err(AsmError::InvalidFormat);
     }
@@ -402,6 +966,8 @@ pub fn parse_assembly_line<'a>(
     let instruction = parts[0].to_uppercase();
     let parser: ParserFn = match instruction.as_str() {
         "DBG" => Box::new(parse_dbg),
+        "SYSCALL" => Box::new(parse_syscall),
+        "HALT" => Box::new(parse_halt),
         "MOV" => Box::new(parse_mov),
         "MSL" => Box::new(parse_mov_shift(true)),
         "MSR" => Box::new(parse_mov_shift(false)),
@@ -417,37 +983,66 @@ pub fn parse_assembly_line<'a>(
         "MODR" => Box::new(parse_arithmetic_reg_reg(ArithmeticOp::Mod)),
         "EXPR" => Box::new(parse_arithmetic_reg_reg(ArithmeticOp::Exp)),
         "SQRTR" => Box::new(parse_arithmetic_reg_reg(ArithmeticOp::Sqrt)),
+        "ADCR" => Box::new(parse_arithmetic_reg_reg(ArithmeticOp::Adc)),
         "LDR" => Box::new(parse_ldr_str(false, false)),
         "STR" => Box::new(parse_ldr_str(false, true)),
         "LDB" => Box::new(parse_ldr_str(true, false)),
         "STB" => Box::new(parse_ldr_str(true, true)),
-        "JMP" => Box::new(parse_jmp(false, labels)),
-        "CJP" => Box::new(parse_jmp(true, labels)),
+        "JMP" => Box::new(parse_jmp(false)),
+        "CJP" => Box::new(parse_jmp(true)),
         "EQ" => Box::new(parse_comparision(CompareOp::Eq)),
         "NEQ" => Box::new(parse_comparision(CompareOp::NotEq)),
         "LT" => Box::new(parse_comparision(CompareOp::Less)),
         "LTE" => Box::new(parse_comparision(CompareOp::LessEq)),
         "GT" => Box::new(parse_comparision(CompareOp::Greater)),
         "GTE" => Box::new(parse_comparision(CompareOp::GreaterEq)),
-
-        "RET" => Box::new(parse_ret),
-        "CALL" => Box::new(parse_call(labels)),
+        "SLT" => Box::new(parse_comparision(CompareOp::SignedLess)),
+        "SLTE" => Box::new(parse_comparision(CompareOp::SignedLessEq)),
+        "SGT" => Box::new(parse_comparision(CompareOp::SignedGreater)),
+        "SGTE" => Box::new(parse_comparision(CompareOp::SignedGreaterEq)),
+        "SDIV" => Box::new(parse_arithmetic(ArithmeticOp::SignedDiv)),
+        "SMOD" => Box::new(parse_arithmetic(ArithmeticOp::SignedMod)),
+        "LDBS" => Box::new(parse_ldbs),
+
+        "RET" => Box::new(parse_ret(false)),
+        "CALL" => Box::new(parse_call(false)),
+        "LRET" => Box::new(parse_ret(true)),
+        "LCALL" => Box::new(parse_call(true)),
+        "AND" => Box::new(parse_bitwise(BitwiseOp::And)),
+        "OR" => Box::new(parse_bitwise(BitwiseOp::Or)),
+        "XOR" => Box::new(parse_bitwise(BitwiseOp::Xor)),
+        "NOT" => Box::new(parse_bitwise(BitwiseOp::Not)),
+        "SHL" => Box::new(parse_bitwise(BitwiseOp::ShiftLeft)),
+        "SHR" => Box::new(parse_bitwise(BitwiseOp::ShiftRight)),
+        "SAR" => Box::new(parse_bitwise(BitwiseOp::ShiftArith)),
+
+        "RTI" => Box::new(parse_rti),
+        "PUSH" => Box::new(parse_push_pop(true)),
+        "POP" => Box::new(parse_push_pop(false)),
+        "ENTER" => Box::new(parse_enter),
+        "LEAVE" => Box::new(parse_leave),
         _ => return Err(AsmError::InvalidInstruction),
     };
 
-    parser(&parts[1..])
+    parser(&parts[1..], labels)
 }
 
-fn parse_comparision(cmp_op: CompareOp) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
+fn parse_comparision(cmp_op: CompareOp) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
         if args.len() != 2 {
-            return Err(AsmError::InvalidInstruction);
+            return Err(AsmError::InvalidOperands);
         }
 
         let r0 = args[0].trim_end_matches(",").parse::<Register>()?;
 
         let (opt_reg, opt_imm) = if args[1].starts_with("#") {
-            (None, Some(parse_immediate(args[1])?))
+            let imm = if cmp_op.is_signed() {
+                signed_immediate(args[1], 5, symbols)?
+            } else {
+                unsigned_immediate(args[1], 5, symbols)?
+            };
+
+            (None, Some(imm))
         } else {
             (Some(args[1].parse::<Register>()?), None)
         };
@@ -456,28 +1051,15 @@ fn parse_comparision(cmp_op: CompareOp) -> impl Fn(&[&str]) -> Result<Instructio
     }
 }
 
-fn parse_jmp<'a>(
-    cond: bool,
-    labels: &'a HashMap<String, u16>,
-) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
+fn parse_jmp(cond: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
         if args.len() != 1 {
-            return Err(AsmError::InvalidInstruction);
+            return Err(AsmError::InvalidOperands);
         }
 
-        let (opt_reg, opt_imm) = if args[0].starts_with("#") {
-            (None, Some(parse_immediate(args[0])?))
-        } else {
-            match args[0].parse::<Register>() {
-                Ok(reg) => (Some(reg), None),
-                Err(_) => {
-                    if let Some(jmp_addr) = labels.get(args[0]) {
-                        (None, Some(jmp_addr.clone()))
-                    } else {
-                        return Err(AsmError::UnresolvedLabel(args[0].to_string()));
-                    }
-                }
-            }
+        let (opt_reg, opt_imm) = match args[0].parse::<Register>() {
+            Ok(reg) => (Some(reg), None),
+            Err(_) => (None, Some(jump_target(args[0], symbols)?)),
         };
 
         if cond {
@@ -488,34 +1070,44 @@ fn parse_jmp<'a>(
     }
 }
 
-fn parse_call<'a>(
-    labels: &'a HashMap<String, u16>,
-) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
+// jump_target reads an 11 bit address, `#expr` or a bare label
+// expression as `loop` or `table+4`
+fn jump_target(arg: &str, symbols: &HashMap<String, i32>) -> Result<u16, AsmError> {
+    if arg.starts_with('#') {
+        return unsigned_immediate(arg, 11, symbols);
+    }
+
+    check_range(arg, parse_expression(arg, symbols)?, 0, (1 << 11) - 1)
+}
+
+// CALL pushes the return address into the stack while
+// LCALL (link) keeps it in the register M
+fn parse_call(is_link: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
         if args.len() != 1 {
-            return Err(AsmError::InvalidInstruction);
+            return Err(AsmError::InvalidOperands);
         }
 
-        let addr = if args[0].starts_with("#") {
-            parse_immediate(args[0])?
-        } else {
-            if let Some(jmp_addr) = labels.get(args[0]) {
-                *jmp_addr
-            } else {
-                return Err(AsmError::UnresolvedLabel(args[0].to_string()));
-            }
+        let (opt_reg, opt_imm) = match args[0].parse::<Register>() {
+            Ok(reg) if !is_link => (Some(reg), None),
+            _ => (None, Some(jump_target(args[0], symbols)?)),
         };
 
-        return Ok(Instruction::CallRet(false, addr));
+        if is_link {
+            // the link call only supports immediate addresses
+            return Ok(Instruction::CallRet(false, opt_imm.unwrap_or_default()));
+        }
+
+        Ok(Instruction::Call(opt_reg, opt_imm))
     }
 }
 
 
 // LDR A, [B, #4]
-fn parse_ldr_str(is_byte: bool, is_str: bool) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
-        if args.len() > 3 {
-            return Err(AsmError::InvalidInstruction);
+fn parse_ldr_str(is_byte: bool, is_str: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
+        if !(2..=3).contains(&args.len()) {
+            return Err(AsmError::InvalidOperands);
         }
 
         let reg_dst = args[0].trim_end_matches(',').parse::<Register>()?;
@@ -528,41 +1120,34 @@ fn parse_ldr_str(is_byte: bool, is_str: bool) -> impl Fn(&[&str]) -> Result<Inst
             }
         }
 
-        let reg_src = args[1].trim_start_matches('[').parse::<Register>()?;
-        let shift = parse_immediate(args[2].trim_end_matches(']'))?;
+        let reg_src = args[1].trim_start_matches('[').trim_end_matches(',').parse::<Register>()?;
+        let shift_arg = args.get(2).ok_or(AsmError::InvalidOperands)?.trim_end_matches(']');
+        let shift = unsigned_immediate(shift_arg, 5, symbols)? as u8;
 
         if is_byte {
-            Ok(Instruction::LdbStb(
-                reg_dst,
-                reg_src,
-                is_str,
-                shift.try_into().map_err(|_| AsmError::InvalidImmediate)?,
-            ))
+            Ok(Instruction::LdbStb(reg_dst, reg_src, is_str, shift))
         } else {
-            Ok(Instruction::LdrStr(
-                reg_dst,
-                reg_src,
-                is_str,
-                shift.try_into().map_err(|_| AsmError::InvalidImmediate)?,
-            ))
+            Ok(Instruction::LdrStr(reg_dst, reg_src, is_str, shift))
         }
     }
 }
 
-fn parse_arithmetic(op: ArithmeticOp) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
-        if args.len() > 2 {
-            return Err(AsmError::InvalidInstruction);
+fn parse_arithmetic(op: ArithmeticOp) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
+        if args.len() != 2 {
+            return Err(AsmError::InvalidOperands);
         }
 
         let reg = args[0].trim_end_matches(',').parse::<Register>()?;
         if args[1].starts_with('#') {
-            Ok(Instruction::Arith(
-                reg,
-                None,
-                Some(parse_immediate(args[1])?),
-                op,
-            ))
+            // signed instructions take a 5 bit two's complement immediate
+            let imm = if is_signed_arith(op) {
+                signed_immediate(args[1], 5, symbols)?
+            } else {
+                unsigned_immediate(args[1], 6, symbols)?
+            };
+
+            Ok(Instruction::Arith(reg, None, Some(imm), op))
         } else {
             Ok(Instruction::Arith(
                 reg,
@@ -574,10 +1159,32 @@ fn parse_arithmetic(op: ArithmeticOp) -> impl Fn(&[&str]) -> Result<Instruction,
     }
 }
 
-fn parse_arithmetic_reg_reg(op: ArithmeticOp) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> { 
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
+// AND A, B | AND A, #1 | NOT A (same as NOT A, A)
+fn parse_bitwise(op: BitwiseOp) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
+        if op == BitwiseOp::Not && args.len() == 1 {
+            let reg = args[0].parse::<Register>()?;
+            return Ok(Instruction::Bitwise(reg, Some(reg), None, op));
+        }
+
+        if args.len() != 2 {
+            return Err(AsmError::InvalidOperands);
+        }
+
+        let reg = args[0].trim_end_matches(',').parse::<Register>()?;
+        if args[1].starts_with('#') {
+            let imm = unsigned_immediate(args[1], 5, symbols)?;
+            Ok(Instruction::Bitwise(reg, None, Some(imm), op))
+        } else {
+            Ok(Instruction::Bitwise(reg, Some(args[1].parse::<Register>()?), None, op))
+        }
+    }
+}
+
+fn parse_arithmetic_reg_reg(op: ArithmeticOp) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> { 
+    move |args: &[&str], _symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
         if args.len() != 3 {
-            return Err(AsmError::InvalidInstruction);
+            return Err(AsmError::InvalidOperands);
         }
 
         let dst_reg = args[0].trim_end_matches(',').parse::<Register>()?;
@@ -588,25 +1195,103 @@ fn parse_arithmetic_reg_reg(op: ArithmeticOp) -> impl Fn(&[&str]) -> Result<Inst
     }
 }
 
-fn parse_immediate(s: &str) -> Result<u16, AsmError> {
-    if s.starts_with("#") {
-        let value = &s[1..];
+// LDBS A, B | LDBS A, [B #4]
+fn parse_ldbs(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    let reg_dst = args
+        .first()
+        .ok_or(AsmError::InvalidOperands)?
+        .trim_end_matches(',')
+        .parse::<Register>()?;
+
+    match args {
+        [_, addr] if !addr.starts_with('[') => {
+            Ok(Instruction::LdbSigned(reg_dst, addr.parse::<Register>()?, 0))
+        }
+        [_, addr, shift] => {
+            let addr_reg = addr.trim_start_matches('[').trim_end_matches(',').parse::<Register>()?;
+            let shift = unsigned_immediate(shift.trim_end_matches(']'), 3, symbols)?;
 
-        if value.starts_with("0x") {
-            let value = &value[2..];
-            dbg!();
-            return u16::from_str_radix(value, 16).map_err(|_| AsmError::InvalidImmediate);
-        } else {
-            return value.parse::<u16>().map_err(|_| AsmError::InvalidImmediate);
+            Ok(Instruction::LdbSigned(reg_dst, addr_reg, shift as u8))
         }
+        _ => Err(AsmError::InvalidOperands),
     }
+}
 
-    Err(AsmError::InvalidImmediate)
+// parse_immediate evaluates `#expr`, the result may be negative
+fn parse_immediate(s: &str, symbols: &HashMap<String, i32>) -> Result<i32, AsmError> {
+    let expr = s
+        .strip_prefix('#')
+        .ok_or_else(|| AsmError::InvalidImmediate(s.to_string()))?;
+    parse_expression(expr, symbols).map_err(|err| match err {
+        AsmError::InvalidImmediate(_) => AsmError::InvalidImmediate(s.to_string()),
+        err => err,
+    })
 }
 
-fn parse_copy(args: &[&str]) -> Result<Instruction, AsmError> {
+fn parse_expression(expr: &str, symbols: &HashMap<String, i32>) -> Result<i32, AsmError> {
+    expr::eval(expr, symbols)
+}
+
+// unsigned_immediate reads an immediate for a `bits` wide field
+fn unsigned_immediate(arg: &str, bits: u32, symbols: &HashMap<String, i32>) -> Result<u16, AsmError> {
+    check_range(arg, parse_immediate(arg, symbols)?, 0, (1 << bits) - 1)
+}
+
+// signed_immediate reads a two's complement immediate for a `bits` wide field
+fn signed_immediate(arg: &str, bits: u32, symbols: &HashMap<String, i32>) -> Result<u16, AsmError> {
+    let half = 1 << (bits - 1);
+    check_range(arg, parse_immediate(arg, symbols)?, -half, half - 1)
+}
+
+fn check_range(token: &str, value: i32, min: i32, max: i32) -> Result<u16, AsmError> {
+    if !(min..=max).contains(&value) {
+        return Err(AsmError::OutOfRange {
+            token: token.to_string(),
+            value,
+            min,
+            max,
+        });
+    }
+
+    Ok(value as u16)
+}
+
+// split_operands splits a line on whitespace, except inside
+// parentheses and character literals so `#(1 + 2)` and `#' '` stay whole
+fn split_operands(line: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut in_char = false;
+    let mut start = None;
+    let mut prev = ' ';
+
+    for (idx, c) in line.char_indices() {
+        match c {
+            '\'' if prev != '\\' => in_char = !in_char,
+            '(' if !in_char => depth += 1,
+            ')' if !in_char && depth > 0 => depth -= 1,
+            _ => {}
+        }
+        prev = c;
+
+        if c.is_whitespace() && depth == 0 && !in_char {
+            if let Some(from) = start.take() {
+                parts.push(&line[from..idx]);
+            }
+        } else if start.is_none() {
+            start = Some(idx);
+        }
+    }
+
+    if let Some(from) = start {
+        parts.push(&line[from..]);
+    }
+    parts
+}
+
+fn parse_copy(args: &[&str], _symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
     if args.len() != 2 {
-        return Err(AsmError::InvalidInstruction);
+        return Err(AsmError::InvalidOperands);
     }
 
     let src_reg = args[0].trim_end_matches(',').parse::<Register>()?;
@@ -615,23 +1300,92 @@ fn parse_copy(args: &[&str]) -> Result<Instruction, AsmError> {
     Ok(Instruction::Cpy(src_reg, dst_reg))
 }
 
-fn parse_ret(_args: &[&str]) -> Result<Instruction, AsmError> {
-    return Ok(Instruction::CallRet(true, 0));
+fn parse_ret(is_link: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |_args: &[&str], _symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
+        if is_link {
+            return Ok(Instruction::CallRet(true, 0));
+        }
+
+        Ok(Instruction::Ret)
+    }
 }
 
-fn parse_dbg(_args: &[&str]) -> Result<Instruction, AsmError> {
-    return Ok(Instruction::Noop);
+fn parse_rti(args: &[&str], _symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    if !args.is_empty() {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    Ok(Instruction::Rti)
 }
 
-fn parse_mov(args: &[&str]) -> Result<Instruction, AsmError> {
+fn parse_push_pop(is_push: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], _symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
+        if args.len() != 1 {
+            return Err(AsmError::InvalidOperands);
+        }
+
+        let reg = args[0].parse::<Register>()?;
+        if is_push {
+            Ok(Instruction::Push(reg))
+        } else {
+            Ok(Instruction::Pop(reg))
+        }
+    }
+}
+
+fn parse_enter(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    if args.len() != 1 {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    let size = unsigned_immediate(args[0], 8, symbols)?;
+    Ok(Instruction::Enter(size as u8))
+}
+
+fn parse_leave(args: &[&str], _symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    if !args.is_empty() {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    Ok(Instruction::Leave)
+}
+
+// DBG takes an optional tag so the host can tell breakpoints apart
+fn parse_dbg(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    match args {
+        [] => Ok(Instruction::Breakpoint(0)),
+        [arg] => Ok(Instruction::Breakpoint(unsigned_immediate(arg, 8, symbols)? as u8)),
+        _ => Err(AsmError::InvalidOperands),
+    }
+}
+
+fn parse_syscall(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    if args.len() != 1 {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    let number = unsigned_immediate(args[0], 8, symbols)?;
+    Ok(Instruction::Syscall(number as u8))
+}
+
+// HALT without a code exits with 0
+fn parse_halt(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    match args {
+        [] => Ok(Instruction::Halt(0)),
+        [arg] => Ok(Instruction::Halt(unsigned_immediate(arg, 8, symbols)? as u8)),
+        _ => Err(AsmError::InvalidOperands),
+    }
+}
+
+fn parse_mov(args: &[&str], symbols: &HashMap<String, i32>) -> Result<Instruction, AsmError> {
     if args.len() != 2 {
-        return Err(AsmError::InvalidInstruction);
+        return Err(AsmError::InvalidOperands);
     }
 
     let reg = args[0].trim_end_matches(',').parse::<Register>()?;
     
     if args[1].starts_with('#') {
-        let imm = parse_immediate(args[1])?;
+        let imm = unsigned_immediate(args[1], 8, symbols)?;
         Ok(Instruction::Mov(
             reg, 
             None, 
@@ -647,33 +1401,222 @@ fn parse_mov(args: &[&str]) -> Result<Instruction, AsmError> {
 
 }
 
-fn parse_mov_shift(dir: bool) -> impl Fn(&[&str]) -> Result<Instruction, AsmError> {
-    move |args: &[&str]| -> Result<Instruction, AsmError> {
+fn parse_mov_shift(dir: bool) -> impl Fn(&[&str], &HashMap<String, i32>) -> Result<Instruction, AsmError> {
+    move |args: &[&str], symbols: &HashMap<String, i32>| -> Result<Instruction, AsmError> {
         if args.len() != 3 {
-            return Err(AsmError::InvalidInstruction);
+            return Err(AsmError::InvalidOperands);
         }
 
         let reg = args[0].trim_end_matches(',').parse::<Register>()?;
 
-        let value = parse_immediate(args[1].trim_start_matches("["))?;
-        let shift = parse_immediate(args[2].trim_end_matches("]"))?;
+        let value = unsigned_immediate(args[1].trim_start_matches("["), 5, symbols)?;
+        let shift = unsigned_immediate(args[2].trim_end_matches("]"), 3, symbols)?;
 
-        Ok(Instruction::MovShift(
-            reg,
-            shift.try_into().map_err(|_| AsmError::InvalidInstruction)?,
-            dir,
-            value,
-        ))
+        Ok(Instruction::MovShift(reg, shift as u8, dir, value))
+    }
+}
+
+// parse_directive returns the bytes of a data directive placed at `addr`
+//
+//     .byte #72, #-1        bytes, from -128 to 255
+//     .word #0xF000, label  little endian words, labels may be offset as msg+2
+//     .ascii "Hi\n"        the string bytes
+//     .asciz "Hi"           followed by a NUL
+//     .space #16            zeros
+//     .align #2             zeros up to the next multiple of 2
+fn parse_directive(line: &str, labels: &HashMap<String, i32>, addr: u16) -> Result<Vec<u8>, AsmError> {
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+
+    match name.to_lowercase().as_str() {
+        ".byte" => data_values(args)?
+            .into_iter()
+            .map(|arg| {
+                let value = check_range(arg, parse_value(arg, labels)?, -128, 255)?;
+                Ok(value as u8)
+            })
+            .collect(),
+        ".word" => {
+            let mut bytes = vec![];
+            for arg in data_values(args)? {
+                bytes.extend(word_value(arg, labels)?.to_le_bytes());
+            }
+            Ok(bytes)
+        }
+        ".ascii" => parse_string(args),
+        ".asciz" => {
+            let mut bytes = parse_string(args)?;
+            bytes.push(0);
+            Ok(bytes)
+        }
+        ".space" => Ok(vec![0; unsigned_immediate(args, 16, labels)? as usize]),
+        ".align" => {
+            let align = check_range(args, parse_immediate(args, labels)?, 1, u16::MAX as i32)?;
+            Ok(vec![0; ((align - addr % align) % align) as usize])
+        }
+        _ => Err(AsmError::InvalidInstruction),
+    }
+}
+
+// parse_value reads `#expr` or a bare expression starting with a
+// label, as `msg` or `msg+4`
+fn parse_value(arg: &str, symbols: &HashMap<String, i32>) -> Result<i32, AsmError> {
+    if arg.starts_with('#') {
+        return parse_immediate(arg, symbols);
+    }
+
+    parse_expression(arg, symbols).map_err(|err| match err {
+        AsmError::InvalidImmediate(_) => AsmError::InvalidImmediate(arg.to_string()),
+        err => err,
+    })
+}
+
+// word_value reads a 16 bit value with `parse_value`, negative
+// ones are stored in two's complement
+fn word_value(arg: &str, symbols: &HashMap<String, i32>) -> Result<u16, AsmError> {
+    check_range(arg, parse_value(arg, symbols)?, i16::MIN as i32, u16::MAX as i32)
+}
+
+// data_values splits the comma separated values of a directive
+fn data_values(args: &str) -> Result<Vec<&str>, AsmError> {
+    let values = split_args(args);
+    if values.is_empty() || values.iter().any(|value| value.is_empty()) {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    Ok(values)
+}
+
+// parse_string reads a double quoted string, with the \n, \r,
+// \t, \0, \\ and \" escapes
+fn parse_string(arg: &str) -> Result<Vec<u8>, AsmError> {
+    let text = arg
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or(AsmError::InvalidOperands)?;
+
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '"' => return Err(AsmError::InvalidOperands),
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err(AsmError::InvalidOperands),
+            },
+            c => c,
+        };
+
+        let mut buf = [0; 4];
+        bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+    }
+
+    Ok(bytes)
+}
+
+// LI A, #0xF000 | LI A, label | LI A, #msg+4
+fn parse_li(args: &[&str], labels: &HashMap<String, i32>) -> Result<Vec<Instruction>, AsmError> {
+    if args.len() != 2 {
+        return Err(AsmError::InvalidOperands);
+    }
+
+    let reg = args[0].trim_end_matches(',').parse::<Register>()?;
+    let addr = word_value(args[1], labels)?;
+    if !expr::has_symbols(args[1]) {
+        return Ok(load_immediate(reg, addr));
+    }
+
+    // 8 + 5 + 3 bits
+    Ok(vec![
+        Instruction::Mov(reg, None, Some(addr >> 8)),
+        Instruction::MovShift(reg, 5, true, (addr >> 3) & 0b11111),
+        Instruction::MovShift(reg, 3, true, addr & 0b111),
+    ])
+}
+
+// load_immediate returns the shortest MOV and MSL sequence
+// that leaves `value` in the register
+fn load_immediate(reg: Register, value: u16) -> Vec<Instruction> {
+    let mut memo = HashMap::new();
+    let mut insts = vec![];
+
+    let mut value = value;
+    while let (_, Some((shift, imm))) = shortest_load(value, &mut memo) {
+        insts.push(Instruction::MovShift(reg, shift, true, imm));
+        value = (value & !imm) >> shift;
+    }
+    insts.push(Instruction::Mov(reg, None, Some(value)));
+
+    insts.reverse();
+    insts
+}
+
+// the (shift, imm) of the MSL ending a load, None when a MOV is enough
+type LoadStep = Option<(u8, u16)>;
+
+// shortest_load returns how many instructions build `value`
+// and the last one of them
+fn shortest_load(value: u16, memo: &mut HashMap<u16, (usize, LoadStep)>) -> (usize, LoadStep) {
+    if value <= 0xFF {
+        return (1, None);
+    }
+    if let Some(found) = memo.get(&value) {
+        return *found;
+    }
+
+    let mut best = (usize::MAX, None);
+    for shift in 0..8u8 {
+        // the bits cleared by the shift must come from the immediate
+        let low = value & ((1 << shift) - 1);
+        if low > 0b11111 {
+            break;
+        }
+
+        for imm in (0..=0b11111).filter(|imm| imm & low == low && imm & !value == 0) {
+            let prev = (value & !imm) >> shift;
+            if prev == value {
+                continue;
+            }
+
+            let (count, _) = shortest_load(prev, memo);
+            if count + 1 < best.0 {
+                best = (count + 1, Some((shift, imm)));
+            }
+        }
     }
+
+    memo.insert(value, best);
+    best
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, fs, path::Path};
 
-    use crate::machine::{ArithmeticOp, CompareOp, Instruction, Register};
+    use crate::machine::{ArithmeticOp, BitwiseOp, CompareOp, Instruction, Register};
 
-    use super::{encode_instruction, parse_assembly_line};
+    use super::{
+        AsmError, Image, ImageError, assemble, assemble_sources, encode_instruction, expand_assembly_line, image_words, parse_assembly_line, parse_immediate,
+        resolve_and_parse_assembly,
+    };
+
+    #[test]
+    fn test_rv16asm_macro() {
+        // a single instruction goes through the same arm as a list, the
+        // arm it used to have named an undefined `$instr` and didn't build
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+        assert_eq!(crate::rv16asm! { "ADD A, #1" }, vec![encode("ADD A, #1")]);
+        assert_eq!(crate::rv16asm! { "ADD A, #1", }, vec![encode("ADD A, #1")]);
+        assert_eq!(
+            crate::rv16asm! { "ADD A, #1", "RET" },
+            vec![encode("ADD A, #1"), encode("RET")]
+        );
+        assert!(crate::rv16asm! {}.is_empty());
+    }
 
     #[test]
     fn test_encode_instruction() {
@@ -843,15 +1786,183 @@ mod test {
         let inst = parse_assembly_line(input, &empty).unwrap();
         assert_eq!(
             inst,
-            Instruction::CallRet(false, 1024)
+            Instruction::Call(None, Some(1024))
+        );
+
+        let input = "CALL C";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Call(Some(Register::C), None)
         );
 
         let input = "RET";
         let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Ret
+        );
+
+        let input = "LCALL #1024";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::CallRet(false, 1024)
+        );
+
+        let input = "LRET";
+        let inst = parse_assembly_line(input, &empty).unwrap();
         assert_eq!(
             inst,
             Instruction::CallRet(true, 0)
         );
+
+        for inst in [
+            Instruction::Call(None, Some(2047)),
+            Instruction::Call(Some(Register::M), None),
+            Instruction::Ret,
+        ] {
+            assert_eq!(Instruction::try_from(encode_instruction(&inst)), Ok(inst));
+        }
+        let input = "RTI";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::Rti);
+        assert_eq!(Instruction::try_from(encode_instruction(&inst)), Ok(Instruction::Rti));
+
+        let input = "XOR B, C";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Bitwise(Register::B, Some(Register::C), None, BitwiseOp::Xor)
+        );
+
+        let input = "SAR A, #3";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Bitwise(Register::A, None, Some(3), BitwiseOp::ShiftArith)
+        );
+
+        let input = "NOT M";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Bitwise(Register::M, Some(Register::M), None, BitwiseOp::Not)
+        );
+
+        assert!(parse_assembly_line("AND A, #32", &empty).is_err());
+
+        for op in [
+            BitwiseOp::And,
+            BitwiseOp::Or,
+            BitwiseOp::Xor,
+            BitwiseOp::Not,
+            BitwiseOp::ShiftLeft,
+            BitwiseOp::ShiftRight,
+            BitwiseOp::ShiftArith,
+        ] {
+            for inst in [
+                Instruction::Bitwise(Register::FLAGS, None, Some(31), op),
+                Instruction::Bitwise(Register::A, Some(Register::BP), None, op),
+            ] {
+                let encoded = encode_instruction(&inst);
+                let text = inst.to_string();
+                assert_eq!(Instruction::try_from(encoded), Ok(inst));
+                let parsed = parse_assembly_line(&text, &empty).unwrap();
+                assert_eq!(encode_instruction(&parsed), encoded);
+            }
+        }
+
+        let input = "SLT A, #-3";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(
+            inst,
+            Instruction::Cmp(Register::A, None, Some(0xFFFD), CompareOp::SignedLess)
+        );
+
+        let input = "LDBS B, [C #7]";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::LdbSigned(Register::B, Register::C, 7));
+
+        assert!(parse_assembly_line("SGT A, #16", &empty).is_err());
+        assert!(parse_assembly_line("SDIV A, #-17", &empty).is_err());
+        assert!(parse_assembly_line("LDBS A, [B #8]", &empty).is_err());
+
+        for inst in [
+            Instruction::Cmp(Register::A, None, Some(0xFFF0), CompareOp::SignedLess),
+            Instruction::Cmp(Register::B, Some(Register::C), None, CompareOp::SignedLessEq),
+            Instruction::Cmp(Register::M, None, Some(15), CompareOp::SignedGreater),
+            Instruction::Cmp(Register::SP, None, Some(0xFFFF), CompareOp::SignedGreaterEq),
+            Instruction::Arith(Register::A, None, Some(0xFFFE), ArithmeticOp::SignedDiv),
+            Instruction::Arith(Register::B, Some(Register::A), None, ArithmeticOp::SignedMod),
+            Instruction::LdbSigned(Register::A, Register::BP, 0),
+            Instruction::LdbSigned(Register::C, Register::SP, 5),
+        ] {
+            let encoded = encode_instruction(&inst);
+            let text = inst.to_string();
+            assert_eq!(Instruction::try_from(encoded), Ok(inst));
+            let parsed = parse_assembly_line(&text, &empty).unwrap();
+            assert_eq!(encode_instruction(&parsed), encoded);
+        }
+
+        let input = "PUSH BP";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::Push(Register::BP));
+
+        let input = "POP FLAGS";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::Pop(Register::FLAGS));
+
+        let input = "ENTER #16";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::Enter(16));
+
+        let input = "LEAVE";
+        let inst = parse_assembly_line(input, &empty).unwrap();
+        assert_eq!(inst, Instruction::Leave);
+
+        for inst in [
+            Instruction::Push(Register::BP),
+            Instruction::Pop(Register::FLAGS),
+            Instruction::Enter(255),
+            Instruction::Leave,
+        ] {
+            assert_eq!(Instruction::try_from(encode_instruction(&inst)), Ok(inst));
+        }
+
+        let inst = parse_assembly_line("DBG", &empty).unwrap();
+        assert_eq!(inst, Instruction::Breakpoint(0));
+
+        let inst = parse_assembly_line("DBG #0xFF", &empty).unwrap();
+        assert_eq!(inst, Instruction::Breakpoint(255));
+
+        assert!(parse_assembly_line("DBG #256", &empty).is_err());
+        assert!(parse_assembly_line("DBG A", &empty).is_err());
+
+        let inst = parse_assembly_line("SYSCALL #3", &empty).unwrap();
+        assert_eq!(inst, Instruction::Syscall(3));
+        assert!(parse_assembly_line("SYSCALL", &empty).is_err());
+
+        let inst = parse_assembly_line("HALT", &empty).unwrap();
+        assert_eq!(inst, Instruction::Halt(0));
+        let inst = parse_assembly_line("halt #3", &empty).unwrap();
+        assert_eq!(inst, Instruction::Halt(3));
+        assert!(parse_assembly_line("HALT #256", &empty).is_err());
+
+        for inst in [
+            Instruction::Breakpoint(0),
+            Instruction::Breakpoint(42),
+            Instruction::Syscall(255),
+            Instruction::Halt(0),
+            Instruction::Halt(200),
+        ] {
+            let encoded = encode_instruction(&inst);
+            assert_ne!(encoded, 0);
+            assert_eq!(Instruction::try_from(encoded), Ok(inst));
+            let parsed = parse_assembly_line(&inst.to_string(), &empty).unwrap();
+            assert_eq!(parsed, inst);
+        }
+
         let input = "ADDR C, A, B";
         let inst = parse_assembly_line(input, &empty).unwrap();
         assert_eq!(
@@ -859,4 +1970,454 @@ mod test {
             Instruction::ArithRegReg(Register::C, Register::A, Register::B, ArithmeticOp::Add)
         )
     }
+
+    #[test]
+    fn test_load_immediate() {
+        // the value the MOV and MSL sequence leaves in the register,
+        // every instruction must survive the encoding
+        let load = |insts: &[Instruction]| {
+            insts.iter().fold(0u16, |value, inst| {
+                assert_eq!(Instruction::try_from(encode_instruction(inst)), Ok(*inst));
+                match *inst {
+                    Instruction::Mov(_, None, Some(imm)) => imm,
+                    Instruction::MovShift(_, shift, true, imm) => (value << shift) | imm,
+                    _ => panic!("unexpected {:?}", inst),
+                }
+            })
+        };
+
+        let empty = HashMap::new();
+        for (value, count) in [(0, 1), (255, 1), (256, 2), (0xF000, 3), (0xF102, 3), (0xFFFF, 3)] {
+            let insts = expand_assembly_line(&format!("LI A, #{}", value), &empty).unwrap();
+            assert_eq!(load(&insts), value);
+            assert_eq!(insts.len(), count, "LI #{}", value);
+        }
+
+        for value in (0..=u16::MAX).step_by(97) {
+            let insts = expand_assembly_line(&format!("li A, #{}", value), &empty).unwrap();
+            assert_eq!(load(&insts), value);
+            assert!(insts.len() <= 3);
+        }
+
+        let insts = expand_assembly_line("LI A, #-2", &empty).unwrap();
+        assert_eq!(load(&insts), 0xFFFE);
+
+        // labels take the same amount of instructions whatever their address
+        let labels = HashMap::from([("low".to_string(), 4), ("high".to_string(), 0xEFD2)]);
+        for (label, addr) in [("low", 4), ("high", 0xEFD2)] {
+            let insts = expand_assembly_line(&format!("LI A, {}", label), &labels).unwrap();
+            assert_eq!(load(&insts), addr);
+            assert_eq!(insts.len(), 3);
+        }
+
+        assert!(matches!(
+            expand_assembly_line("LI A, missing", &labels),
+            Err(AsmError::UnresolvedLabel(label)) if label == "missing"
+        ));
+        assert!(expand_assembly_line("LI A", &empty).is_err());
+        assert!(expand_assembly_line("LI #3, A", &empty).is_err());
+
+        // the disassembly of the expansion assembles back to it
+        for inst in expand_assembly_line("LI A, #0xF102", &empty).unwrap() {
+            assert_eq!(parse_assembly_line(&inst.to_string(), &empty).unwrap(), inst);
+        }
+
+        // a forward label keeps the addresses of the following lines
+        let code = "LI B, end\nLI C, #0xF000\nend:\nMOV A, #1\nLI C, end";
+        let insts = resolve_and_parse_assembly(code).unwrap();
+        assert_eq!(insts.len(), 10);
+        assert_eq!(load(&insts[..3]), 12);
+        assert_eq!(load(&insts[3..6]), 0xF000);
+        assert_eq!(insts[6], Instruction::Mov(Register::A, None, Some(1)));
+        assert_eq!(load(&insts[7..]), 12);
+    }
+
+    #[test]
+    fn test_assemble_diagnostics() {
+        let code = "start:\nMOV D, #1\n\tADD A, #0xZZ\nJMP nowhere\nFOO A\nADD A\nstart:\nLI B, later\nRET";
+        let diagnostics = assemble("bad.s", code).unwrap_err();
+
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.len, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (2, 5, 1, "invalid register `D`"),
+                (3, 9, 5, "invalid immediate `#0xZZ`"),
+                (4, 5, 7, "undefined symbol `nowhere`"),
+                (5, 1, 3, "unknown instruction `FOO`"),
+                (6, 5, 1, "invalid operands for `ADD`"),
+                (7, 1, 5, "label `start` is already defined"),
+                (8, 7, 5, "undefined symbol `later`"),
+            ]
+        );
+
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "error: invalid immediate `#0xZZ`\n --> bad.s:3:9\n  |\n3 | \tADD A, #0xZZ\n  | \t       ^^^^^"
+        );
+
+        // the token is looked for as a whole word after the mnemonic
+        let diagnostics = assemble("a.s", "ADD A, AX").unwrap_err();
+        assert_eq!((diagnostics[0].column, diagnostics[0].len), (8, 2));
+
+        assert!(assemble("a.s", "JMP end\nend:\nRET").is_ok());
+    }
+
+    #[test]
+    fn test_expressions() {
+        let symbols = HashMap::from([("msg".to_string(), 0x20), ("SIZE".to_string(), 4)]);
+        let imm = |arg: &str| parse_immediate(arg, &symbols);
+
+        assert_eq!(imm("#'A'"), Ok(65));
+        assert_eq!(imm("#'\\n'"), Ok(10));
+        assert_eq!(imm("#0b101"), Ok(5));
+        assert_eq!(imm("#1+2*3"), Ok(7));
+        assert_eq!(imm("#(1 + 2) * 3"), Ok(9));
+        assert_eq!(imm("#1<<4|2"), Ok(18));
+        assert_eq!(imm("#~0&0xFF"), Ok(0xFF));
+        assert_eq!(imm("#-SIZE"), Ok(-4));
+        assert_eq!(imm("#msg+SIZE/2"), Ok(0x22));
+        assert_eq!(imm("#nowhere"), Err(AsmError::UnresolvedLabel("nowhere".to_string())));
+        for bad in ["#", "#(1", "#1+", "#1/0", "#''", "#0x", "#1 2"] {
+            assert_eq!(imm(bad), Err(AsmError::InvalidImmediate(bad.to_string())));
+        }
+
+        // spaces inside parentheses and characters don't split the operands
+        assert_eq!(
+            parse_assembly_line("MOV A, #' '", &symbols),
+            Ok(Instruction::Mov(Register::A, None, Some(32)))
+        );
+        assert_eq!(
+            parse_assembly_line("ADD A, #(SIZE - 1)", &symbols),
+            Ok(Instruction::Arith(Register::A, None, Some(3), ArithmeticOp::Add))
+        );
+        assert_eq!(
+            parse_assembly_line("JMP msg+2", &symbols),
+            Ok(Instruction::Jmp(None, Some(0x22)))
+        );
+
+        let code = r#"
+            .equ TERMINAL, #0xF000
+            .set COUNT, #2
+            .set COUNT, #COUNT+1
+            LI A, #TERMINAL+1
+            MOV B, #COUNT*LAST
+            LI C, #msg+1
+        msg:
+            .asciz "Hi"
+            .word msg+1, #'a'
+            .equ LAST, #'z'-'a'
+        "#;
+        let insts: Vec<Instruction> = image_words(&assemble("equ.s", code).unwrap())
+            .into_iter()
+            .take(7)
+            .map(|word| Instruction::try_from(word).unwrap())
+            .collect();
+        // LI with a symbol takes the fixed 3 instruction form
+        let defined = HashMap::from([("TERMINAL".to_string(), 0xF000), ("msg".to_string(), 14)]);
+        let mut expected = expand_assembly_line("LI A, #TERMINAL+1", &defined).unwrap();
+        expected.push(Instruction::Mov(Register::B, None, Some(75)));
+        expected.extend(expand_assembly_line("LI C, #msg+1", &defined).unwrap());
+        assert_eq!(insts, expected);
+
+        let image = assemble("equ.s", code).unwrap();
+        assert_eq!(&image[14..21], &[b'H', b'i', 0, 15, 0, b'a', 0]);
+
+        // negative constants keep their sign in the signed fields
+        let code = ".equ NEG, #-1\nSLT A, #NEG\nSDIV A, #NEG*2\n.word #NEG, NEG-1";
+        let literals = "SLT A, #-1\nSDIV A, #-2\n.word #-1, #-2";
+        assert_eq!(assemble("neg.s", code).unwrap(), assemble("neg.s", literals).unwrap());
+        let diagnostics = assemble("neg.s", ".equ NEG, #-1\nMOV A, #NEG").unwrap_err();
+        assert_eq!(diagnostics[0].message, "`#NEG` is -1, out of range, expected 0 to 255");
+
+        let code = "MOV A, #256\nADD A, #64\nSUB A, #-1\nEQ A, #32\nSLT A, #-17\nJMP #2048\nMSL A, [#32 #8]\nMSR A, [#1 #8]\n.equ X, #Y\n.equ Y, #1\n.equ Y, #2\n.space #Z\n.equ Z, #1\n.equ 1X, #1";
+        let found: Vec<_> = assemble("bad.s", code)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.line, d.column, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, 8, "`#256` is out of range, expected 0 to 255".to_string()),
+                (2, 8, "`#64` is out of range, expected 0 to 63".to_string()),
+                (3, 8, "`#-1` is out of range, expected 0 to 63".to_string()),
+                (4, 7, "`#32` is out of range, expected 0 to 31".to_string()),
+                (5, 8, "`#-17` is out of range, expected -16 to 15".to_string()),
+                (6, 5, "`#2048` is out of range, expected 0 to 2047".to_string()),
+                (7, 9, "`#32` is out of range, expected 0 to 31".to_string()),
+                (8, 12, "`#8` is out of range, expected 0 to 7".to_string()),
+                (9, 10, "`Y` must be defined before this line".to_string()),
+                (11, 6, "symbol `Y` is already defined".to_string()),
+                (12, 9, "`Z` must be defined before this line".to_string()),
+                (14, 6, "invalid operands for `.EQU`".to_string()),
+            ]
+        );
+
+        let diagnostics = assemble("a.s", "MOV A, #(1 << 8)").unwrap_err();
+        assert_eq!(diagnostics[0].message, "`#(1 << 8)` is 256, out of range, expected 0 to 255");
+    }
+
+    #[test]
+    fn test_macros() {
+        let code = r#"
+        .macro PUSH2 a, b
+            PUSH \a
+            PUSH \b
+        .endm
+        .macro WAIT reg, count
+            MOV \reg, \count
+        %%loop:
+            SUB \reg, #1
+            NEQ \reg, #0
+            CJP %%loop
+        loop\@:
+        .endm
+        .macro SAVE_ALL
+            PUSH2 A, B
+            push2 C, M
+        .endm
+            SAVE_ALL
+            WAIT A, #3
+            WAIT B, #(1 + 1)
+        "#;
+        let expected = "
+            PUSH A
+            PUSH B
+            PUSH C
+            PUSH M
+            MOV A, #3
+        loop.0:
+            SUB A, #1
+            NEQ A, #0
+            CJP loop.0
+        loop0:
+            MOV B, #(1 + 1)
+        loop.1:
+            SUB B, #1
+            NEQ B, #0
+            CJP loop.1
+        loop1:
+        ";
+        assert_eq!(
+            resolve_and_parse_assembly(code).unwrap(),
+            resolve_and_parse_assembly(expected).unwrap()
+        );
+
+        // the macros a body defines exist after its expansion
+        let code = ".macro OUTER name\n.macro \\name\nRET\n.endm\n.endm\nOUTER INNER\nINNER";
+        assert_eq!(resolve_and_parse_assembly(code).unwrap(), vec![Instruction::Ret]);
+
+        // invoking it again replaces them, a plain redefinition is an error
+        let code = ".macro OUTER name, value\n.macro \\name\nMOV A, \\value\n.endm\n.endm\nOUTER INNER, #1\nINNER\nOUTER INNER, #2\nINNER";
+        assert_eq!(
+            resolve_and_parse_assembly(code).unwrap(),
+            vec![
+                Instruction::Mov(Register::A, None, Some(1)),
+                Instruction::Mov(Register::A, None, Some(2)),
+            ]
+        );
+        let code = ".macro TWICE\nRET\n.endm\n.macro TWICE\nRET\n.endm";
+        let diagnostics = assemble("twice.s", code).unwrap_err();
+        assert_eq!(diagnostics[0].message, "macro `TWICE` is already defined");
+
+        let code = ".macro SET reg, value\nMOV \\reg, \\value\n.endm\nSET A\nSET D, #1\nSET A, #300\n.macro SELF\nSELF\n.endm\nSELF\n.endm\n.macro OPEN\nRET";
+        let diagnostics = assemble("bad.s", code).unwrap_err();
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.len, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (4, 1, 3, "macro `SET` takes 2 arguments but 1 were given"),
+                (5, 5, 1, "invalid register `D`"),
+                (6, 8, 4, "`#300` is out of range, expected 0 to 255"),
+                (10, 1, 4, "macro `SELF` nests too deep, does it invoke itself?"),
+                (11, 1, 5, "`.endm` without a matching `.macro`"),
+                (12, 1, 6, "`.macro` without a matching `.endm`"),
+            ]
+        );
+
+        // the errors of an expansion point at the invocation
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "error: invalid register `D`\n --> bad.s:5:5\n  |\n5 | SET D, #1\n  |     ^\n  = note: in this expansion of `SET`: MOV D, #1"
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let lib_path = testdata.join("lib").join("int_to_str.s");
+        let lib = fs::read_to_string(&lib_path).unwrap();
+        let main_path = testdata.join("loop.s");
+        let main = fs::read_to_string(&main_path).unwrap();
+
+        // including the library is the same as assembling it after the program
+        let without_include = main.replace(".include \"lib/int_to_str.s\"", "");
+        assert_eq!(
+            assemble(&main_path.to_string_lossy(), &main).unwrap(),
+            assemble_sources(
+                &[("loop.s", &without_include), (&lib_path.to_string_lossy(), &lib)],
+                &[]
+            )
+            .unwrap()
+            .bytes
+        );
+
+        // the files share their labels, constants and macros
+        let sources = [
+            ("a.s", ".equ SEVEN, #7\n.macro SET reg\nMOV \\reg, #SEVEN\n.endm\nCALL second\nRET"),
+            ("b.s", "second:\nSET A\nJMP a_end"),
+            ("c.s", "a_end:\nRET"),
+        ];
+        let image = image_words(&assemble_sources(&sources, &[]).unwrap().bytes);
+        let insts: Vec<_> = image.into_iter().map(|word| Instruction::try_from(word).unwrap()).collect();
+        assert_eq!(
+            insts,
+            vec![
+                Instruction::Call(None, Some(4)),
+                Instruction::Ret,
+                Instruction::Mov(Register::A, None, Some(7)),
+                Instruction::Jmp(None, Some(8)),
+                Instruction::Ret,
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("rust16vm-includes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("a.s"), ".include \"b.s\"").unwrap();
+        fs::write(dir.join("b.s"), "RET\n.include \"a.s\"").unwrap();
+        fs::write(dir.join("lib").join("bad.s"), "MOV D, #1").unwrap();
+
+        let code = ".include \"a.s\"\n.include \"bad.s\"\n.include \"nowhere.s\"\n.include nowhere.s";
+        let main_path = dir.join("main.s").to_string_lossy().to_string();
+        let found: Vec<_> = assemble_sources(&[(&main_path, code)], &[dir.join("lib")])
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.file, d.line, d.message))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let b_path = dir.join("b.s").to_string_lossy().to_string();
+        let bad_path = dir.join("lib").join("bad.s").to_string_lossy().to_string();
+        assert_eq!(
+            found,
+            vec![
+                (main_path.clone(), 3, "`nowhere.s` not found next to the file or in the include paths".to_string()),
+                (main_path, 4, "invalid operands for `.INCLUDE`".to_string()),
+                (b_path, 2, "`a.s` is already being included, the includes make a cycle".to_string()),
+                (bad_path, 1, "invalid register `D`".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let code = r#"
+            LI A, table
+            ADD FLAGS, #1
+        msg:
+            .asciz "Hi\n\"x\""
+            .byte #1, #-1
+            .align #2
+        table:
+            .word msg, #0x1234, end
+            .ascii "ab"
+            .space #3
+        end:
+            .align #2
+            RET
+        "#;
+
+        let image = assemble("data.s", code).unwrap();
+
+        // the 3 LI instructions and the halt, then the string at 8
+        let mut expected = vec![];
+        let labels = HashMap::from([("table".to_string(), 18)]);
+        let mut insts = expand_assembly_line("LI A, table", &labels).unwrap();
+        insts.push(parse_assembly_line("ADD FLAGS, #1", &labels).unwrap());
+        for inst in insts {
+            expected.extend(encode_instruction(&inst).to_le_bytes());
+        }
+        expected.extend(b"Hi\n\"x\"\0");
+        expected.extend([1, 0xFF]);
+        expected.push(0);
+        expected.extend([8, 0, 0x34, 0x12, 29, 0]);
+        expected.extend(b"ab");
+        expected.extend([0, 0, 0]);
+        expected.push(0);
+        expected.extend(encode_instruction(&Instruction::Ret).to_le_bytes());
+        assert_eq!(image, expected);
+
+        // only the instructions are reported as code
+        let image = assemble_sources(&[("data.s", code)], &[]).unwrap();
+        assert_eq!(image.code, vec![(0, 8), (30, 2)]);
+        assert_eq!(Image::from_bin(&image.to_bin()), Ok(image.clone()));
+
+        // the image is padded to whole words
+        assert_eq!(assemble("a.s", ".byte #1").unwrap(), vec![1, 0]);
+
+        // it can't reach the end of the address space
+        assert_eq!(assemble("a.s", ".space #65534").unwrap().len(), 65534);
+        let diagnostics = assemble("a.s", ".space #65534\n.byte #1").unwrap_err();
+        assert_eq!(diagnostics[0].message, "the program does not fit in 64KiB");
+
+        // the commas and spaces in character literals are not separators
+        let code = ".byte #',', #' ', end\nend:\n.word #',', end";
+        assert_eq!(assemble("a.s", code).unwrap(), vec![b',', b' ', 3, b',', 0, 3, 0, 0]);
+
+        let code = ".byte #256\n.ascii \"open\n.ascii \"a\"b\"\n.word nowhere\n.foo #1\n.space\n.space #1\nRET\n.align #0";
+        let found: Vec<_> = assemble("bad.s", code)
+            .unwrap_err()
+            .into_iter()
+            .map(|d| (d.line, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, "`#256` is out of range, expected -128 to 255".to_string()),
+                (2, "invalid operands for `.ASCII`".to_string()),
+                (3, "invalid operands for `.ASCII`".to_string()),
+                (4, "undefined symbol `nowhere`".to_string()),
+                (5, "unknown directive `.foo`".to_string()),
+                (6, "invalid immediate ``".to_string()),
+                (8, "instruction at the odd address 0x0003, place `.align #2` before it".to_string()),
+                (9, "`#0` is out of range, expected 1 to 65535".to_string()),
+            ]
+        );
+
+        // the instruction list can't hold data
+        assert!(resolve_and_parse_assembly(".byte #1").is_err());
+    }
+
+    #[test]
+    fn test_bin_files() {
+        let image = assemble_sources(&[("a.s", "RET\nmsg:\n.ascii \"Hi\"\nRET")], &[]).unwrap();
+        let bin = image.to_bin();
+        assert_eq!(&bin[..10], b"RV16PROG\x01\x00");
+        assert_eq!(Image::from_bin(&bin), Ok(image.clone()));
+
+        // the raw instruction words older versions of asm wrote
+        let legacy: Vec<u8> = image.bytes.clone();
+        assert_eq!(Image::from_bin(&legacy), Err(ImageError::InvalidMagic));
+
+        let mut newer = bin.clone();
+        newer[8] = 2;
+        assert_eq!(Image::from_bin(&newer), Err(ImageError::UnsupportedVersion(2)));
+        assert_eq!(Image::from_bin(&bin[..13]), Err(ImageError::Truncated));
+        assert_eq!(Image::from_bin(&bin[..bin.len() - 1]), Err(ImageError::OddLength));
+
+        let mut past = bin.clone();
+        past.truncate(bin.len() - image.bytes.len());
+        assert_eq!(
+            Image::from_bin(&past),
+            Err(ImageError::SpanOutOfImage { addr: 0, len: 2 })
+        );
+    }
 }
+
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use super::{AsmError, diagnostic::Diagnostic, expr, parse_string};
+
+// the nesting allowed to macro invocations, past it the
+// macro most likely invokes itself
+const MAX_MACRO_DEPTH: usize = 64;
+
+// Line is a source line ready to assemble, with the macro invocations
+// replaced by their bodies and the included files by their lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    // the text to assemble
+    pub text: String,
+    // the file, 1-based line and source of the line, for the expanded
+    // lines the outermost invocation they come from
+    pub file: Rc<str>,
+    pub number: usize,
+    pub source: String,
+    // the macro invoked by `source`, when the line comes from one
+    pub expanded_from: Option<String>,
+}
+
+impl Line {
+    // diagnostic locates `err` on the line, the lines of an expansion
+    // point at the invocation and note the expanded text
+    pub fn diagnostic(&self, err: &AsmError) -> Diagnostic {
+        let file = &self.file;
+        match &self.expanded_from {
+            Some(name) => {
+                Diagnostic::from_expansion(file, self.number, &self.source, name, &self.text, err)
+            }
+            None => Diagnostic::from_error(file, self.number, &self.source, err),
+        }
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+// preprocess expands the macros and the includes of the `sources`,
+// (file, code) pairs whose lines follow each other
+//
+//     .macro PUSH2 a, b
+//         PUSH \a
+//         PUSH \b
+//     .endm
+//
+// `\name` is replaced by the argument given for `name`, `\@` by a number
+// unique to each expansion and `%%label` by a label unique to it. Bodies
+// may invoke other macros and define new ones.
+//
+// `.include "lib.s"` is replaced by the lines of the file, looked for
+// next to the including file and then in the `include_paths`. The files
+// are returned in the order they were read
+pub fn preprocess(
+    sources: &[(&str, &str)],
+    include_paths: &[PathBuf],
+) -> (Vec<Line>, Vec<Rc<str>>, Vec<Diagnostic>) {
+    let mut preprocessor = Preprocessor {
+        include_paths,
+        including: vec![],
+        files: vec![],
+        macros: HashMap::new(),
+        expansions: 0,
+        lines: vec![],
+        diagnostics: vec![],
+    };
+
+    for (file, code) in sources {
+        let path = fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file));
+        preprocessor.including.push(path);
+        let lines = preprocessor.file_lines(file, code);
+        preprocessor.process(lines, 0);
+        preprocessor.including.pop();
+    }
+
+    (preprocessor.lines, preprocessor.files, preprocessor.diagnostics)
+}
+
+struct Preprocessor<'a> {
+    include_paths: &'a [PathBuf],
+    // the files being read, an include of one of them is a cycle
+    including: Vec<PathBuf>,
+    files: Vec<Rc<str>>,
+    // by upper case name, macros are invoked as instructions
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    lines: Vec<Line>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Preprocessor<'_> {
+    fn file_lines(&mut self, file: &str, code: &str) -> Vec<Line> {
+        let file: Rc<str> = Rc::from(file);
+        self.files.push(file.clone());
+
+        code.lines()
+            .enumerate()
+            .map(|(idx, source)| Line {
+                text: source.to_string(),
+                file: file.clone(),
+                number: idx + 1,
+                source: source.to_string(),
+                expanded_from: None,
+            })
+            .collect()
+    }
+
+    fn process(&mut self, lines: Vec<Line>, depth: usize) {
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            let text = line.text.trim();
+            let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+            if name.eq_ignore_ascii_case(".macro") {
+                let body = take_body(&mut lines);
+                let result = match body {
+                    Some(body) => self.define(args, body, line.expanded_from.is_some()),
+                    None => Err(AsmError::UnterminatedMacro),
+                };
+                if let Err(err) = result {
+                    self.diagnostics.push(line.diagnostic(&err));
+                }
+            } else if name.eq_ignore_ascii_case(".endm") {
+                self.diagnostics.push(line.diagnostic(&AsmError::UnmatchedEndm));
+            } else if name.eq_ignore_ascii_case(".include") {
+                if let Err(err) = self.include(&line, args, depth) {
+                    self.diagnostics.push(line.diagnostic(&err));
+                }
+            } else if self.macros.contains_key(&name.to_uppercase()) {
+                if let Err(err) = self.invoke(&line, name, args, depth) {
+                    self.diagnostics.push(line.diagnostic(&err));
+                }
+            } else {
+                self.lines.push(line);
+            }
+        }
+    }
+
+    // define reads `NAME a, b` and keeps the macro for the next lines.
+    // A definition in an expansion replaces the previous one, so the
+    // macro holding it can be invoked again
+    fn define(&mut self, args: &str, body: Vec<String>, expanded: bool) -> Result<(), AsmError> {
+        let mut words = args
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|word| !word.is_empty());
+        let name = words.next().ok_or(AsmError::InvalidOperands)?;
+        let params: Vec<String> = words.map(str::to_string).collect();
+
+        if !expr::is_symbol(name) || !params.iter().all(|param| expr::is_symbol(param)) {
+            return Err(AsmError::InvalidOperands);
+        }
+        if !expanded && self.macros.contains_key(&name.to_uppercase()) {
+            return Err(AsmError::DuplicateMacro(name.to_string()));
+        }
+
+        self.macros.insert(name.to_uppercase(), Macro { params, body });
+        Ok(())
+    }
+
+    fn invoke(&mut self, line: &Line, name: &str, args: &str, depth: usize) -> Result<(), AsmError> {
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(AsmError::MacroTooDeep(name.to_string()));
+        }
+
+        let args = split_args(args);
+        let mac = &self.macros[&name.to_uppercase()];
+        if args.len() != mac.params.len() {
+            return Err(AsmError::MacroArguments {
+                name: name.to_string(),
+                expected: mac.params.len(),
+                found: args.len(),
+            });
+        }
+
+        let expansion = self.expansions;
+        self.expansions += 1;
+
+        let expanded = mac
+            .body
+            .iter()
+            .map(|text| Line {
+                text: substitute(text, &mac.params, &args, expansion),
+                file: line.file.clone(),
+                number: line.number,
+                source: line.source.clone(),
+                // the location stays on the outermost invocation
+                expanded_from: line
+                    .expanded_from
+                    .clone()
+                    .or_else(|| Some(name.to_string())),
+            })
+            .collect();
+
+        self.process(expanded, depth + 1);
+        Ok(())
+    }
+
+    // include reads `"lib.s"` and processes the lines of the file
+    fn include(&mut self, line: &Line, args: &str, depth: usize) -> Result<(), AsmError> {
+        let name = parse_string(args.trim())
+            .ok()
+            .and_then(|name| String::from_utf8(name).ok())
+            .ok_or(AsmError::InvalidOperands)?;
+
+        let path = self
+            .find_include(&line.file, &name)
+            .ok_or_else(|| AsmError::IncludeNotFound(name.clone()))?;
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.including.contains(&canonical) {
+            return Err(AsmError::IncludeCycle(name));
+        }
+
+        let code = fs::read_to_string(&path).map_err(|err| AsmError::IncludeFailed {
+            name,
+            reason: err.to_string(),
+        })?;
+
+        self.including.push(canonical);
+        let lines = self.file_lines(&path.to_string_lossy(), &code);
+        self.process(lines, depth);
+        self.including.pop();
+        Ok(())
+    }
+
+    // find_include looks for `name` next to `file`, then in the include paths
+    fn find_include(&self, file: &str, name: &str) -> Option<PathBuf> {
+        let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+        std::iter::once(dir)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+}
+
+// take_body consumes the lines up to the `.endm` closing a definition,
+// the definitions nested in it included. None when it is missing
+fn take_body(lines: &mut impl Iterator<Item = Line>) -> Option<Vec<String>> {
+    let mut body = vec![];
+    let mut nested = 0;
+
+    for line in lines {
+        let name = line.text.split_whitespace().next().unwrap_or_default();
+        if name.eq_ignore_ascii_case(".macro") {
+            nested += 1;
+        } else if name.eq_ignore_ascii_case(".endm") {
+            if nested == 0 {
+                return Some(body);
+            }
+            nested -= 1;
+        }
+
+        body.push(line.text);
+    }
+
+    None
+}
+
+// split_args splits the comma separated arguments of an invocation
+// or a data directive, the commas in parentheses, brackets and quotes
+// don't count
+pub fn split_args(args: &str) -> Vec<&str> {
+    if args.trim().is_empty() {
+        return vec![];
+    }
+
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    let mut prev = ' ';
+
+    for (idx, c) in args.char_indices() {
+        match c {
+            '"' | '\'' if prev != '\\' => match quote {
+                None => quote = Some(c),
+                Some(open) if open == c => quote = None,
+                _ => {}
+            },
+            '(' | '[' if quote.is_none() => depth += 1,
+            ')' | ']' if quote.is_none() && depth > 0 => depth -= 1,
+            ',' if quote.is_none() && depth == 0 => {
+                parts.push(args[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+
+    parts.push(args[start..].trim());
+    parts
+}
+
+// substitute fills in the parameters and the unique labels of a body line
+fn substitute(text: &str, params: &[String], args: &[&str], expansion: usize) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(idx) = rest.find(['\\', '%']) {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if let Some(after) = rest.strip_prefix("\\@") {
+            out.push_str(&expansion.to_string());
+            rest = after;
+            continue;
+        }
+
+        let (prefix, after) = rest.split_at(if rest.starts_with("%%") { 2 } else { 1 });
+        let len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let word = &after[..len];
+
+        match prefix {
+            "\\" if !word.is_empty() => match params.iter().position(|param| param == word) {
+                Some(pos) => out.push_str(args[pos]),
+                None => out.push_str(&rest[..1 + len]),
+            },
+            "%%" if !word.is_empty() => out.push_str(&format!("{}.{}", word, expansion)),
+            _ => {
+                out.push_str(prefix);
+                rest = after;
+                continue;
+            }
+        }
+
+        rest = &after[len..];
+    }
+
+    out.push_str(rest);
+    out
+}
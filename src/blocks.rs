@@ -0,0 +1,133 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{machine::Instruction, memory::Addressable};
+
+// longest straight-line run translated into a single block
+const MAX_BLOCK_LEN: usize = 64;
+
+// Block is a straight-line run of decoded instructions starting at
+// `start`, the instruction `idx` lives at `start + idx * 2`. Only the
+// last instruction may be a jump, a call or a return
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub(crate) start: u16,
+    pub(crate) instructions: Vec<(u16, Instruction)>,
+}
+
+impl Block {
+    pub(crate) fn pc_of(&self, idx: usize) -> u16 {
+        self.start.wrapping_add(idx as u16 * 2)
+    }
+}
+
+// the instructions that always move PC somewhere else end a block,
+// the ones that may write PC (MOV PC, ..., POP PC) are detected when
+// the block runs
+fn ends_block(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Jmp(..)
+            | Instruction::CondJmp(..)
+            | Instruction::CallRet(..)
+            | Instruction::Call(..)
+            | Instruction::Ret
+            | Instruction::Rti
+            | Instruction::Syscall(_)
+    )
+}
+
+// BlockCache keeps the translated blocks by start address. Any write
+// to a byte covered by a block drops every block, self-modifying
+// code is rare enough for this to be cheaper than tracking each one
+pub(crate) struct BlockCache {
+    blocks: HashMap<u16, Rc<Block>>,
+    // one bit per address covered by a block
+    code: Vec<u64>,
+    // set when a write dropped the blocks, so the running
+    // block stops before executing stale instructions
+    invalidated: bool,
+}
+
+impl BlockCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            code: vec![0; (1 << 16) / 64],
+            invalidated: false,
+        }
+    }
+
+    // block returns the block starting at `pc`, translating it on the
+    // first use. None when the instruction at `pc` can't be translated
+    // (misaligned, not decodable or inside a device region)
+    pub(crate) fn block<M: Addressable>(&mut self, memory: &M, pc: u16) -> Option<Rc<Block>> {
+        if let Some(block) = self.blocks.get(&pc) {
+            return Some(block.clone());
+        }
+
+        let block = Rc::new(translate(memory, pc)?);
+        for offset in 0..block.instructions.len() as u16 * 2 {
+            let addr = pc.wrapping_add(offset) as usize;
+            self.code[addr / 64] |= 1 << (addr % 64);
+        }
+
+        self.blocks.insert(pc, block.clone());
+        Some(block)
+    }
+
+    pub(crate) fn invalidate(&mut self, addr: u16, len: u16) {
+        let is_code = (0..len).any(|offset| {
+            let addr = addr.wrapping_add(offset) as usize;
+            self.code[addr / 64] & (1 << (addr % 64)) != 0
+        });
+
+        if is_code {
+            self.clear();
+            self.invalidated = true;
+        }
+    }
+
+    // take_invalidated reports whether the blocks were
+    // dropped since the last call
+    pub(crate) fn take_invalidated(&mut self) -> bool {
+        std::mem::take(&mut self.invalidated)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.code.fill(0);
+    }
+}
+
+fn translate<M: Addressable>(memory: &M, start: u16) -> Option<Block> {
+    if start & 1 != 0 {
+        return None;
+    }
+
+    let mut instructions = vec![];
+    let mut pc = start;
+    while instructions.len() < MAX_BLOCK_LEN {
+        // devices may change their bytes without being written
+        if !memory.is_cacheable(pc) || !memory.is_cacheable(pc.wrapping_add(1)) {
+            break;
+        }
+
+        let Some(raw) = memory.read2(pc) else {
+            break;
+        };
+        let Ok(inst) = Instruction::try_from(raw) else {
+            break;
+        };
+
+        instructions.push((raw, inst));
+        match pc.checked_add(2) {
+            Some(next) if !ends_block(&inst) => pc = next,
+            _ => break,
+        }
+    }
+
+    (!instructions.is_empty()).then_some(Block {
+        start,
+        instructions,
+    })
+}
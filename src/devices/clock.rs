@@ -0,0 +1,39 @@
+use super::Device;
+
+const COUNTER_SIZE: usize = 8;
+
+// CycleCounter exposes the machine cycle count to the guest, the
+// count is a little endian u64 so it can be read with 4 LDRs:
+//
+//     offset 0 -> bits 0..15
+//     offset 2 -> bits 16..31
+//     offset 4 -> bits 32..47
+//     offset 6 -> bits 48..63
+//
+// the counter is read only, writes are ignored
+#[derive(Default)]
+pub struct CycleCounter {
+    cycles: u64,
+}
+
+impl CycleCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for CycleCounter {
+    fn read(&self, offset: usize) -> u8 {
+        if offset >= COUNTER_SIZE {
+            return 0;
+        }
+
+        (self.cycles >> (offset * 8)) as u8
+    }
+
+    fn write(&mut self, _offset: usize, _value: u8) {}
+
+    fn clock(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+}
@@ -2,6 +2,7 @@ use std::{borrow::Cow, collections::VecDeque, sync::{Arc, Mutex}, time::Duration
 
 use crossterm::event::{poll, read, Event, KeyCode};
 use super::Device;
+use crate::interrupts::IrqLine;
 
 const BUFFER_EMPTY: u8 = 0;
 const BUFFER_NOT_EMPTY: u8 = 1;
@@ -10,15 +11,26 @@ const BUFFER_NOT_EMPTY: u8 = 1;
 pub struct InnerKeyboard {
     buffer: VecDeque<u8>,
     flags: u8,
+    // raised every time a key is pushed into the buffer
+    irq: Option<IrqLine>,
 }
 
 pub struct Keyboard(Arc<Mutex<InnerKeyboard>>);
 
 impl Keyboard {
     pub fn new() -> Self {
+        Self::start(None)
+    }
+
+    pub fn with_irq(irq: IrqLine) -> Self {
+        Self::start(Some(irq))
+    }
+
+    fn start(irq: Option<IrqLine>) -> Self {
         let inner = Arc::new(Mutex::new(InnerKeyboard {
             buffer: VecDeque::new(),
             flags: BUFFER_EMPTY,
+            irq,
         }));
 
         async_std::task::spawn(non_blocking_keyboard(inner.clone()));
@@ -39,6 +51,9 @@ async fn non_blocking_keyboard(keyboard: Arc<Mutex<InnerKeyboard>>) {
                             kb.buffer.push_back(c as u8);
                             kb.flags = BUFFER_NOT_EMPTY;
                             println!("buffer not empty: {:?}", kb.buffer);
+                            if let Some(irq) = &kb.irq {
+                                irq.raise();
+                            }
                         }
                         keyboard.lock().unwrap().buffer.push_back(c as u8);
                     }
@@ -1,3 +1,4 @@
+pub mod clock;
 pub mod keyboard;
 pub mod terminal;
 pub mod screen;
@@ -5,4 +6,18 @@ pub mod screen;
 pub trait Device {
     fn read(&self, offset: usize) -> u8;
     fn write(&mut self, offset: usize, value: u8);
+
+    // clock is called after every instruction with
+    // the total amount of cycles the machine consumed
+    fn clock(&mut self, _cycles: u64) {}
+
+    // save_state opts the device into machine snapshots, devices
+    // without state to keep (or that can't restore it) return None
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Err("device does not support snapshots".to_string())
+    }
 }
@@ -140,6 +140,24 @@ impl Device for Terminal256 {
             _ => (),
         }
     }
+
+    // buffer | cursor x | cursor y | flags
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let mut state = self.buffer.to_vec();
+        state.extend_from_slice(&[self.cursor.0, self.cursor.1, self.flags]);
+        Some(state)
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != TERM_BUFFER_SIZE + 3 {
+            return Err(format!("invalid terminal state size: {}", state.len()));
+        }
+
+        self.buffer.copy_from_slice(&state[..TERM_BUFFER_SIZE]);
+        self.cursor = (state[TERM_BUFFER_SIZE], state[TERM_BUFFER_SIZE + 1]);
+        self.flags = state[TERM_BUFFER_SIZE + 2];
+        Ok(())
+    }
 }
 
 impl Drop for Terminal256 {
@@ -0,0 +1,117 @@
+use std::ops::RangeInclusive;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU16, Ordering},
+};
+
+// amount of IRQ lines, each one is a bit in the pending mask
+pub const IRQ_LINES: u8 = 16;
+
+// the vector table holds one u16 handler address per IRQ line,
+// placed right before the terminal MMIO region:
+//
+//     0xEFE0 -> handler for IRQ 0
+//     0xEFE2 -> handler for IRQ 1
+//             ...
+//     0xEFFE -> handler for IRQ 15
+//
+// a handler address of 0 means the line is not handled and
+// the interrupt is dropped
+pub const IVT_BASE: u16 = 0xEFE0;
+
+// the traps raised by instructions have their own vectors, right
+// before the 8 bytes the vm binary maps the cycle counter at
+//
+//     0xEFD0 -> SYSCALL handler
+//     0xEFD2 -> privilege violation handler
+//     0xEFD4 -> kernel SP, the stack the traps and interrupts taken
+//               in user mode switch to (0 keeps the current stack)
+pub const SYSCALL_VECTOR: u16 = 0xEFD0;
+pub const PRIVILEGE_VECTOR: u16 = 0xEFD2;
+pub const KERNEL_SP_VECTOR: u16 = 0xEFD4;
+
+// the vectors and the vector table can only be written in
+// supervisor mode, user writes raise a privilege violation. The
+// kernel adds its own regions with Machine::add_supervisor_region
+pub const SUPERVISOR_MEMORY: RangeInclusive<u16> = SYSCALL_VECTOR..=0xEFFF;
+
+pub fn vector_addr(line: u8) -> u16 {
+    IVT_BASE + (line as u16) * 2
+}
+
+// InterruptController keeps the set of pending IRQ lines, it is
+// shared with the devices (that may live in other threads) through
+// the IrqLine handles
+#[derive(Clone, Default)]
+pub struct InterruptController {
+    pending: Arc<AtomicU16>,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn line(&self, line: u8) -> Result<IrqLine, String> {
+        if line >= IRQ_LINES {
+            return Err(format!("invalid irq line: {}", line));
+        }
+
+        Ok(IrqLine {
+            line,
+            pending: self.pending.clone(),
+        })
+    }
+
+    // pending returns the mask of the lines waiting to be served
+    pub fn pending(&self) -> u16 {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn set_pending(&self, mask: u16) {
+        self.pending.store(mask, Ordering::SeqCst);
+    }
+
+    pub fn is_pending(&self, line: u8) -> bool {
+        self.pending.load(Ordering::SeqCst) & (1 << line) != 0
+    }
+
+    // take clears and returns the pending line with the highest
+    // priority, lower line numbers are served first
+    pub fn take(&self) -> Option<u8> {
+        let mut pending = self.pending.load(Ordering::SeqCst);
+        loop {
+            if pending == 0 {
+                return None;
+            }
+
+            let line = pending.trailing_zeros() as u8;
+            match self.pending.compare_exchange(
+                pending,
+                pending & !(1 << line),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(line),
+                Err(curr) => pending = curr,
+            }
+        }
+    }
+}
+
+// IrqLine is the handle a device uses to request an interrupt
+#[derive(Clone)]
+pub struct IrqLine {
+    line: u8,
+    pending: Arc<AtomicU16>,
+}
+
+impl IrqLine {
+    pub fn number(&self) -> u8 {
+        self.line
+    }
+
+    pub fn raise(&self) {
+        self.pending.fetch_or(1 << self.line, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+
+// JournalEntry holds what a single step overwrote: the registers
+// and cycle count before the step and the previous value of every
+// memory byte it wrote, in write order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub registers: [u16; 8],
+    pub cycles: u64,
+    pub writes: Vec<(u16, u8)>,
+}
+
+// Journal is the undo log of the machine, it keeps the entries of
+// the last `capacity` steps and drops the oldest ones when full
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    capacity: usize,
+    // entry of the step being executed
+    current: Option<JournalEntry>,
+}
+
+impl Journal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            current: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn begin(&mut self, registers: [u16; 8], cycles: u64) {
+        self.current = Some(JournalEntry {
+            registers,
+            cycles,
+            writes: vec![],
+        });
+    }
+
+    // record keeps the value `addr` held before being written,
+    // it does nothing outside of a step
+    pub fn record(&mut self, addr: u16, old: u8) {
+        if let Some(entry) = self.current.as_mut() {
+            entry.writes.push((addr, old));
+        }
+    }
+
+    pub fn commit(&mut self) {
+        let Some(entry) = self.current.take() else {
+            return;
+        };
+
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<JournalEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.current = None;
+    }
+}
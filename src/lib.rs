@@ -1,7 +1,15 @@
 #![feature(io_const_error)]
 
 pub mod asm;
+pub mod blocks;
+pub mod interrupts;
+pub mod journal;
 pub mod machine;
 pub mod mmio;
 pub mod memory;
+pub mod run;
 pub mod devices;
+pub mod snapshot;
+pub mod syscall;
+pub mod timing;
+pub mod trace;
@@ -1,5 +1,16 @@
 #[allow(dead_code)]
 use super::memory::Addressable;
+use crate::blocks::BlockCache;
+use crate::interrupts;
+use crate::journal::Journal;
+use crate::run::{DEADLINE_INTERVAL, RunConfig, StopReason};
+use crate::snapshot::{
+    SNAPSHOT_MAGIC, SNAPSHOT_VERSION, Snapshot, SnapshotError, SnapshotReader, SnapshotWriter,
+};
+use crate::timing::CycleCosts;
+use crate::syscall::{SyscallAction, SyscallContext, SyscallHandler};
+use crate::trace::{TraceSink, Tracer};
+use std::{collections::HashSet, time::Instant};
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Eq, Ord)]
 #[repr(usize)]
@@ -18,6 +29,15 @@ pub enum Register {
     // bit 3 - if set to 1 then
     // [not eq|eq|less|less_eq|greater|greater_eq]
     // instruction is true
+    // bit 4 - if set to 1 interrupts are enabled
+    // bit 5 - carry, the last arithmetic operation overflowed as unsigned
+    //         (or needed a borrow in a subtraction)
+    // bit 6 - overflow, the last arithmetic operation overflowed as signed
+    // bit 7 - zero, the last arithmetic result is 0
+    // bit 8 - negative, the last arithmetic result has bit 15 set
+    // bit 9 - if set to 1 a division (or modulo) by zero happened
+    // bit 10 - if set to 1 the machine runs in user mode, bits 0, 4
+    //          and 10 can only be changed in supervisor mode
     FLAGS,
 }
 
@@ -37,7 +57,7 @@ impl ToString for Register {
 }
 
 impl TryFrom<usize> for Register {
-    type Error = String;
+    type Error = DecodeError;
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         match value {
@@ -49,7 +69,7 @@ impl TryFrom<usize> for Register {
             5 => Ok(Register::PC),
             6 => Ok(Register::BP),
             7 => Ok(Register::FLAGS),
-            _ => Err(format!("invalid register: {}", value)),
+            _ => Err(DecodeError::InvalidRegister),
         }
     }
 }
@@ -63,6 +83,12 @@ pub enum ArithmeticOp {
     Mod,
     Exp,
     Sqrt,
+    // add with carry, sums the FLAGS carry bit as well
+    Adc,
+    // two's complement division and remainder, the remainder
+    // takes the sign of the dividend
+    SignedDiv,
+    SignedMod,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
@@ -73,10 +99,27 @@ pub enum CompareOp {
     LessEq,    // 011
     Greater,   // 100
     GreaterEq, // 101
+    // two's complement comparisons, encoded in the signed group (1110)
+    SignedLess,
+    SignedLessEq,
+    SignedGreater,
+    SignedGreaterEq,
+}
+
+impl CompareOp {
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            CompareOp::SignedLess
+                | CompareOp::SignedLessEq
+                | CompareOp::SignedGreater
+                | CompareOp::SignedGreaterEq
+        )
+    }
 }
 
 impl TryFrom<usize> for CompareOp {
-    type Error = String;
+    type Error = DecodeError;
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
         match value {
@@ -86,7 +129,168 @@ impl TryFrom<usize> for CompareOp {
             3 => Ok(CompareOp::LessEq),
             4 => Ok(CompareOp::Greater),
             5 => Ok(CompareOp::GreaterEq),
-            _ => Err(format!("invalid compare opcode: {}", value)),
+            _ => Err(DecodeError::IllegalOpcode),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
+pub enum BitwiseOp {
+    And,        // 000
+    Or,         // 001
+    Xor,        // 010
+    Not,        // 011
+    ShiftLeft,  // 100
+    ShiftRight, // 101 logical, fills with zeros
+    ShiftArith, // 110 arithmetic, fills with the sign bit
+}
+
+impl TryFrom<usize> for BitwiseOp {
+    type Error = DecodeError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(BitwiseOp::And),
+            1 => Ok(BitwiseOp::Or),
+            2 => Ok(BitwiseOp::Xor),
+            3 => Ok(BitwiseOp::Not),
+            4 => Ok(BitwiseOp::ShiftLeft),
+            5 => Ok(BitwiseOp::ShiftRight),
+            6 => Ok(BitwiseOp::ShiftArith),
+            _ => Err(DecodeError::IllegalOpcode),
+        }
+    }
+}
+
+/// errors found while decoding a raw instruction word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    IllegalOpcode,
+    InvalidRegister,
+}
+
+/// errors returned by `Machine::step`, every variant carries the
+/// address of the faulting instruction (`pc`) and its raw word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    // the opcode (or one of its sub operations) is not part of the ISA
+    IllegalOpcode { pc: u16, raw: u16 },
+    // a register field does not name any of the 8 registers
+    InvalidRegister { pc: u16, raw: u16 },
+    // the instruction at pc could not be read from memory,
+    // raw is always 0 given that nothing was fetched
+    FetchOutOfBounds { pc: u16, raw: u16 },
+    // the memory refused a write at `addr`, either it is
+    // marked as read-only or it is outside the memory
+    ReadOnlyWrite { pc: u16, raw: u16, addr: u16 },
+    // the memory has nothing to be read at `addr`
+    ReadOutOfBounds { pc: u16, raw: u16, addr: u16 },
+    DivisionByZero { pc: u16, raw: u16 },
+    ArithmeticOverflow { pc: u16, raw: u16 },
+    // a push would move SP below the stack region limit
+    StackOverflow { pc: u16, raw: u16 },
+    // a pop would move SP above the stack region base
+    StackUnderflow { pc: u16, raw: u16 },
+    // user code touched privileged state and there is
+    // no privilege violation handler installed
+    PrivilegeViolation { pc: u16, raw: u16 },
+    // SYSCALL without a handler installed
+    UnhandledSyscall { pc: u16, raw: u16 },
+}
+
+impl VmError {
+    pub fn pc(&self) -> u16 {
+        match self {
+            VmError::IllegalOpcode { pc, .. }
+            | VmError::InvalidRegister { pc, .. }
+            | VmError::FetchOutOfBounds { pc, .. }
+            | VmError::ReadOnlyWrite { pc, .. }
+            | VmError::ReadOutOfBounds { pc, .. }
+            | VmError::DivisionByZero { pc, .. }
+            | VmError::ArithmeticOverflow { pc, .. }
+            | VmError::StackOverflow { pc, .. }
+            | VmError::StackUnderflow { pc, .. }
+            | VmError::PrivilegeViolation { pc, .. }
+            | VmError::UnhandledSyscall { pc, .. } => *pc,
+        }
+    }
+
+    pub fn raw(&self) -> u16 {
+        match self {
+            VmError::IllegalOpcode { raw, .. }
+            | VmError::InvalidRegister { raw, .. }
+            | VmError::FetchOutOfBounds { raw, .. }
+            | VmError::ReadOnlyWrite { raw, .. }
+            | VmError::ReadOutOfBounds { raw, .. }
+            | VmError::DivisionByZero { raw, .. }
+            | VmError::ArithmeticOverflow { raw, .. }
+            | VmError::StackOverflow { raw, .. }
+            | VmError::StackUnderflow { raw, .. }
+            | VmError::PrivilegeViolation { raw, .. }
+            | VmError::UnhandledSyscall { raw, .. } => *raw,
+        }
+    }
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cause = match self {
+            VmError::IllegalOpcode { .. } => "illegal opcode".to_string(),
+            VmError::InvalidRegister { .. } => "invalid register".to_string(),
+            VmError::FetchOutOfBounds { .. } => "fetch outside memory".to_string(),
+            VmError::ReadOnlyWrite { addr, .. } => format!("write refused at {:#06x}", addr),
+            VmError::ReadOutOfBounds { addr, .. } => format!("read outside memory at {:#06x}", addr),
+            VmError::DivisionByZero { .. } => "division by zero".to_string(),
+            VmError::ArithmeticOverflow { .. } => "arithmetic overflow".to_string(),
+            VmError::StackOverflow { .. } => "stack overflow".to_string(),
+            VmError::StackUnderflow { .. } => "stack underflow".to_string(),
+            VmError::PrivilegeViolation { .. } => "privilege violation".to_string(),
+            VmError::UnhandledSyscall { .. } => "unhandled syscall".to_string(),
+        };
+
+        write!(f, "{} @ {:#06x} ({:#06x})", cause, self.pc(), self.raw())
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// faults found while executing an already decoded instruction,
+// `step` attaches the pc and raw word to build a `VmError`
+enum Fault {
+    IllegalOpcode,
+    InvalidRegister,
+    ReadOnlyWrite(u16),
+    ReadOutOfBounds(u16),
+    DivisionByZero,
+    ArithmeticOverflow,
+    StackOverflow,
+    StackUnderflow,
+    PrivilegeViolation,
+    UnhandledSyscall,
+}
+
+impl From<DecodeError> for Fault {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::IllegalOpcode => Fault::IllegalOpcode,
+            DecodeError::InvalidRegister => Fault::InvalidRegister,
+        }
+    }
+}
+
+impl Fault {
+    fn at(self, pc: u16, raw: u16) -> VmError {
+        match self {
+            Fault::IllegalOpcode => VmError::IllegalOpcode { pc, raw },
+            Fault::InvalidRegister => VmError::InvalidRegister { pc, raw },
+            Fault::ReadOnlyWrite(addr) => VmError::ReadOnlyWrite { pc, raw, addr },
+            Fault::ReadOutOfBounds(addr) => VmError::ReadOutOfBounds { pc, raw, addr },
+            Fault::DivisionByZero => VmError::DivisionByZero { pc, raw },
+            Fault::ArithmeticOverflow => VmError::ArithmeticOverflow { pc, raw },
+            Fault::StackOverflow => VmError::StackOverflow { pc, raw },
+            Fault::StackUnderflow => VmError::StackUnderflow { pc, raw },
+            Fault::PrivilegeViolation => VmError::PrivilegeViolation { pc, raw },
+            Fault::UnhandledSyscall => VmError::UnhandledSyscall { pc, raw },
         }
     }
 }
@@ -96,7 +300,7 @@ impl TryFrom<usize> for CompareOp {
 /// MSL | MSR - Move immediate to register shifting register value
 /// ADD | SUB | MUL | Div - Arithmetic Operations
 /// LDR | STR - operations on the memory
-#[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
 pub enum Instruction {
     Noop,
 
@@ -114,9 +318,11 @@ pub enum Instruction {
     // op: 00 (add) 01 (sub) 10 (mul) 11 (div)
     Arith(Register, Option<Register>, Option<u16>, ArithmeticOp),
 
-    // Executes one of the arithmetic operations (add, sub, mul, div, mod, exp, sqrt)
+    // Executes one of the arithmetic operations (add, sub, mul, div, mod, exp, sqrt, adc)
     // Format: 1010 | dst_reg(3) | op(3) | src_1reg(3) | src2_reg(3)
-    // op: 000 (add) 001 (sub) 010 (mul) 011 (div) 100 (mod) 101 (exp) 110 (sqrt)
+    // op: 000 (add) 001 (sub) 010 (mul) 011 (div) 100 (mod) 101 (exp) 110 (sqrt) 111 (adc)
+    // every arithmetic operation wraps around and updates the FLAGS carry,
+    // overflow, zero and negative bits
     ArithRegReg(Register, Register, Register, ArithmeticOp),
 
     // Load or Store a u16 the register value in the memory
@@ -143,15 +349,75 @@ pub enum Instruction {
 
     // Compare a base register with another register or with an immediate
     // Foarmat: 0111 | reg (3) | cmp (3) | mode (1) | [mode == 1] reg(3), [mode == 0]imm(5)
+    //
+    // Signed comparisons and the signed division/modulo share the signed group
+    // Format: 1110 | reg(3) | op(3) | mode(1) | [mode == 1]reg(3), [mode == 0]imm(5)
+    // op: 000 (slt) 001 (slte) 010 (sgt) 011 (sgte) 100 (sdiv) 101 (smod) 110 (ldbs)
+    // the immediate is sign extended, so it ranges from -16 to 15
     Cmp(Register, Option<Register>, Option<u16>, CompareOp),
 
     // Jump to a specific address, but set the return instruction addr inside register RT
+    // this is the "link" call, a nested call overwrites the return address in M
     // Foarmat: 1011 | ret (1) | address (11)
     CallRet(bool, u16),
+
+    // Push the return instruction addr into the stack and jump
+    // to a specific address, calls can be nested
+    // Format: 1100 | mode (1) | [mode == 1]reg(3) | [mode == 0]imm(11)
+    Call(Option<Register>, Option<u16>),
+
+    // Pop the return instruction addr from the stack into PC
+    // Format: 1111 | 0101 | ...
+    Ret,
+
+    // Executes a bitwise or shift operation between a register and another
+    // register or an immediate, NOT stores the complement of the operand
+    // Format: 1101 | reg(3) | op(3) | mode(1) | [mode == 1]reg(3), [mode == 0]imm(5)
+    // op: 000 (and) 001 (or) 010 (xor) 011 (not) 100 (shl) 101 (shr) 110 (sar)
+    Bitwise(Register, Option<Register>, Option<u16>, BitwiseOp),
+
+    // Return from interrupt, pops PC and then FLAGS from the stack
+    // Format: 1111 | 0000 | ...
+    Rti,
+
+    // Decrements SP by 2 and stores the register value at SP
+    // Format: 1111 | 0001 | reg(8)
+    Push(Register),
+
+    // Loads the value at SP into the register and increments SP by 2
+    // Format: 1111 | 0010 | reg(8)
+    Pop(Register),
+
+    // Sets up a stack frame: pushes BP, copies SP into BP and
+    // reserves `size` bytes for locals
+    // Format: 1111 | 0011 | size(8)
+    Enter(u8),
+
+    // Tears down the frame created by ENTER: copies BP into SP and pops BP
+    // Format: 1111 | 0100 | ...
+    Leave,
+
+    // Load a byte from memory sign extending it into the register
+    // Format: 1110 | reg(3) | 110 | reg(3) | shift(3)
+    LdbSigned(Register, Register, u8),
+
+    // Software breakpoint, stops the machine with State::Breakpoint(tag)
+    // and resumes at the next instruction
+    // Format: 1111 | 0110 | tag(8)
+    Breakpoint(u8),
+
+    // Traps into the supervisor through the syscall vector, pushing
+    // FLAGS, the address of the next instruction and `number`
+    // Format: 1111 | 0111 | number(8)
+    Syscall(u8),
+
+    // Halts the machine with an exit code, privileged in user mode
+    // Format: 1111 | 1000 | code(8)
+    Halt(u8),
 }
 
 impl TryFrom<u16> for Instruction {
-    type Error = String;
+    type Error = DecodeError;
 
     fn try_from(inst: u16) -> Result<Self, Self::Error> {
         let opcode = inst & 0b1111;
@@ -199,6 +465,7 @@ impl TryFrom<u16> for Instruction {
                     0b100 => ArithmeticOp::Mod,
                     0b101 => ArithmeticOp::Exp,
                     0b110 => ArithmeticOp::Sqrt,
+                    0b111 => ArithmeticOp::Adc,
                     _ => unreachable!(),
                 };
                 let fst_reg = Register::try_from(((inst >> 10) & 0b111) as usize)?;
@@ -291,21 +558,159 @@ impl TryFrom<u16> for Instruction {
                 let is_ret = ((inst >> 4) & 0b1) == 1;
                 Ok(Instruction::CallRet(is_ret, (inst >> 5) as u16))
             }
-            _ => Err(format!("unexpected instruction: {:#06x}", inst)),
+            0b1101 => {
+                let reg = Register::try_from(((inst >> 4) & 0b111) as usize)?;
+                let op = BitwiseOp::try_from(((inst >> 7) & 0b111) as usize)?;
+                let reg_mode = (inst >> 10) & 0b1 == 1;
+                if reg_mode {
+                    let src_reg = Register::try_from(((inst >> 11) & 0b111) as usize)?;
+                    return Ok(Instruction::Bitwise(reg, Some(src_reg), None, op));
+                }
+
+                let imm = (inst >> 11) & 0b11111;
+                Ok(Instruction::Bitwise(reg, None, Some(imm), op))
+            }
+            0b1100 => {
+                let is_reg_mode = (inst >> 4) & 0b1 == 1;
+                if is_reg_mode {
+                    let reg = Register::try_from(((inst >> 5) & 0b111) as usize)?;
+                    return Ok(Instruction::Call(Some(reg), None));
+                }
+
+                let imm = (inst >> 5) & 0b11111111111;
+                Ok(Instruction::Call(None, Some(imm)))
+            }
+            0b1110 => {
+                let reg = Register::try_from(((inst >> 4) & 0b111) as usize)?;
+                let op = (inst >> 7) & 0b111;
+                if op == 0b110 {
+                    let addr_reg = Register::try_from(((inst >> 10) & 0b111) as usize)?;
+                    return Ok(Instruction::LdbSigned(reg, addr_reg, (inst >> 13) as u8));
+                }
+
+                let reg_mode = (inst >> 10) & 0b1 == 1;
+                let (opt_reg, opt_imm) = if reg_mode {
+                    let src_reg = Register::try_from(((inst >> 11) & 0b111) as usize)?;
+                    (Some(src_reg), None)
+                } else {
+                    // the imm5 lives in the top bits, so an arithmetic
+                    // shift sign extends it
+                    (None, Some(((inst as i16) >> 11) as u16))
+                };
+
+                match op {
+                    0b000 => Ok(Instruction::Cmp(reg, opt_reg, opt_imm, CompareOp::SignedLess)),
+                    0b001 => Ok(Instruction::Cmp(reg, opt_reg, opt_imm, CompareOp::SignedLessEq)),
+                    0b010 => Ok(Instruction::Cmp(reg, opt_reg, opt_imm, CompareOp::SignedGreater)),
+                    0b011 => Ok(Instruction::Cmp(reg, opt_reg, opt_imm, CompareOp::SignedGreaterEq)),
+                    0b100 => Ok(Instruction::Arith(reg, opt_reg, opt_imm, ArithmeticOp::SignedDiv)),
+                    0b101 => Ok(Instruction::Arith(reg, opt_reg, opt_imm, ArithmeticOp::SignedMod)),
+                    _ => Err(DecodeError::IllegalOpcode),
+                }
+            }
+            0b1111 => {
+                let operand = inst >> 8;
+                match (inst >> 4) & 0b1111 {
+                    0b0000 => Ok(Instruction::Rti),
+                    0b0001 => Ok(Instruction::Push(Register::try_from(operand as usize)?)),
+                    0b0010 => Ok(Instruction::Pop(Register::try_from(operand as usize)?)),
+                    0b0011 => Ok(Instruction::Enter(operand as u8)),
+                    0b0100 => Ok(Instruction::Leave),
+                    0b0101 => Ok(Instruction::Ret),
+                    0b0110 => Ok(Instruction::Breakpoint(operand as u8)),
+                    0b0111 => Ok(Instruction::Syscall(operand as u8)),
+                    0b1000 => Ok(Instruction::Halt(operand as u8)),
+                    _ => Err(DecodeError::IllegalOpcode),
+                }
+            }
+            _ => Err(DecodeError::IllegalOpcode),
         }
     }
 }
 
+// register written by the instruction, memory writes
+// and the implicit FLAGS updates are not included
+fn written_register(inst: &Instruction) -> Option<Register> {
+    match *inst {
+        Instruction::Mov(reg, ..)
+        | Instruction::MovShift(reg, ..)
+        | Instruction::Arith(reg, ..)
+        | Instruction::ArithRegReg(reg, ..)
+        | Instruction::LdbSigned(reg, ..)
+        | Instruction::Bitwise(reg, ..)
+        | Instruction::Pop(reg) => Some(reg),
+        Instruction::LdrStr(reg, _, false, _) | Instruction::LdbStb(reg, _, false, _) => Some(reg),
+        _ => None,
+    }
+}
+
+// tells if any of the `len` bytes at `addr` falls in the
+// vectors or one of the [start, end] `regions`
+fn is_supervisor_memory(regions: &[(u16, u16)], addr: u16, len: u16) -> bool {
+    (0..len).any(|offset| {
+        let at = addr.wrapping_add(offset);
+        interrupts::SUPERVISOR_MEMORY.contains(&at)
+            || regions.iter().any(|(start, end)| (*start..=*end).contains(&at))
+    })
+}
+
+// one entry per u16 aligned address
+const DECODE_CACHE_SIZE: usize = 1 << 15;
+
+const FLAG_INTERRUPT_ENABLE: u16 = 4;
+const FLAG_CARRY: u16 = 5;
+const FLAG_OVERFLOW: u16 = 6;
+const FLAG_ZERO: u16 = 7;
+const FLAG_NEGATIVE: u16 = 8;
+const FLAG_DIV_BY_ZERO: u16 = 9;
+const FLAG_USER_MODE: u16 = 10;
+// the FLAGS bits user code can't change: halt, IE and the mode
+const PRIVILEGED_FLAGS: u16 = 0b1 | (1 << FLAG_INTERRUPT_ENABLE) | (1 << FLAG_USER_MODE);
+const ARITH_FLAGS_MASK: u16 =
+    (1 << FLAG_CARRY) | (1 << FLAG_OVERFLOW) | (1 << FLAG_ZERO) | (1 << FLAG_NEGATIVE);
+
 pub struct Machine<M: Addressable> {
     registers: [u16; 8],
     memory: M,
     is_debug: bool,
+    // address of the guest routine that handles divisions by zero,
+    // when absent the machine halts
+    div_trap_handler: Option<u16>,
+    // (limit, base) the stack grows down from base and
+    // PUSH/POP/ENTER/LEAVE fault when leaving the region
+    stack_region: Option<(u16, u16)>,
+    // [start, end] regions user code can't write nor execute,
+    // besides the vectors: the kernel code, data and stack
+    supervisor_regions: Vec<(u16, u16)>,
+    // cycles consumed since the machine started and
+    // the timing model used to count them
+    cycles: u64,
+    costs: CycleCosts,
+    // undo log of the last steps, only kept when enabled
+    journal: Option<Journal>,
+    tracer: Option<Tracer>,
+    // decoded instructions by address (PC / 2), filled as the program
+    // runs. Writes of the guest invalidate the entries they overlap,
+    // the code marked as read-only is never invalidated as it can't
+    // be written. Allocated on the first fetch while enabled
+    decode_cache: Vec<Option<(u16, Instruction)>>,
+    decode_cache_enabled: bool,
+    // translated basic blocks, only used by step_block
+    blocks: BlockCache,
+    // serves SYSCALL on the host before the guest kernel
+    syscalls: Option<Box<dyn SyscallHandler>>,
+    // FLAGS of the SYSCALL being served on the host, the writes
+    // of the handler are checked in the mode of its caller
+    syscall_flags: u16,
+    // set by HALT or when a syscall handler exits the program
+    exit_code: Option<u16>,
 }
 
 #[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
 pub enum State {
     Continue,
-    Debug,
+    // a DBG instruction was executed, holds its tag
+    Breakpoint(u8),
     Stop,
 }
 
@@ -315,21 +720,146 @@ impl<M: Addressable> Machine<M> {
             registers: [0; 8],
             memory: mem,
             is_debug: false,
+            div_trap_handler: None,
+            stack_region: None,
+            supervisor_regions: vec![],
+            cycles: 0,
+            costs: CycleCosts::default(),
+            journal: None,
+            tracer: None,
+            decode_cache: vec![],
+            decode_cache_enabled: true,
+            blocks: BlockCache::new(),
+            syscalls: None,
+            syscall_flags: 0,
+            exit_code: None,
         }
     }
 
     pub fn new_debug(mem: M, is_debug: bool) -> Self {
-        Self { 
-            registers: [0; 8],
-            memory: mem,
-            is_debug: is_debug,
-        }
+        let mut machine = Self::new(mem);
+        machine.is_debug = is_debug;
+        machine
     }
 
     pub fn set_register(&mut self, reg: Register, value: u16) {
         self.registers[reg as usize] = value;
     }
 
+    // set_div_trap_handler makes divisions by zero jump to `addr`
+    // instead of halting the machine, the handler can return to
+    // the instruction after the division with RTI
+    pub fn set_div_trap_handler(&mut self, addr: u16) {
+        self.div_trap_handler = Some(addr);
+    }
+
+    // set_stack_region bounds the stack to [limit, base], pushing
+    // below `limit` raises a stack overflow and popping above `base`
+    // a stack underflow. SP is not changed.
+    pub fn set_stack_region(&mut self, limit: u16, base: u16) {
+        self.stack_region = Some((limit, base));
+    }
+
+    // add_supervisor_region protects [start, end] from user mode, a
+    // user write into it or a jump to it raises a privilege violation.
+    // It holds the kernel image and the kernel stack, the vectors are
+    // always protected
+    pub fn add_supervisor_region(&mut self, start: u16, end: u16) {
+        self.supervisor_regions.push((start, end));
+    }
+
+    // set_cycle_costs replaces the timing model, the cycles
+    // already consumed are kept
+    pub fn set_cycle_costs(&mut self, costs: CycleCosts) {
+        self.costs = costs;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // set_syscall_handler serves the SYSCALL instructions on the host,
+    // the numbers the handler leaves unhandled trap into the guest
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscalls = Some(handler);
+    }
+
+    // exit_code holds the code the program exited with, by HALT or a
+    // syscall handler. None while running or when it halted by
+    // setting the halt bit of FLAGS
+    pub fn exit_code(&self) -> Option<u16> {
+        self.exit_code
+    }
+
+    // set_decode_cache turns the decoded instruction cache on or
+    // off, it is enabled by default and allocated on the first fetch
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache_enabled = enabled;
+        self.decode_cache = vec![];
+    }
+
+    // enable_journal records the registers and memory bytes each step
+    // overwrites, keeping the last `capacity` steps so they can be
+    // undone with step_back. Device side effects and interrupts raised
+    // meanwhile are not undone
+    pub fn enable_journal(&mut self, capacity: usize) {
+        self.journal = Some(Journal::new(capacity));
+    }
+
+    pub fn disable_journal(&mut self) {
+        self.journal = None;
+    }
+
+    // set_trace_sink hands an event to `sink` after every step, with
+    // the registers the step changed and the memory it read and wrote
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.tracer = Some(Tracer::new(sink));
+    }
+
+    pub fn clear_trace_sink(&mut self) {
+        self.tracer = None;
+    }
+
+    // amount of steps that can be undone
+    pub fn history_len(&self) -> usize {
+        self.journal.as_ref().map_or(0, Journal::len)
+    }
+
+    // step_back undoes the last recorded step, returns false
+    // when there is nothing to undo
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.journal.as_mut().and_then(Journal::pop) else {
+            return false;
+        };
+
+        // the devices are not journaled, writing them back
+        // would run their side effects again
+        for (addr, old) in entry.writes.into_iter().rev() {
+            if !self.memory.is_cacheable(addr) {
+                continue;
+            }
+            self.memory.write(addr, old);
+            self.invalidate_code(addr, 1);
+        }
+
+        self.registers = entry.registers;
+        self.cycles = entry.cycles;
+        self.memory.clock(self.cycles);
+        true
+    }
+
+    // run_back_to undoes steps until PC is `pc`, at least one step is
+    // undone. Returns false when the history ran out before reaching it
+    pub fn run_back_to(&mut self, pc: u16) -> bool {
+        while self.step_back() {
+            if self.registers[Register::PC as usize] == pc {
+                return true;
+            }
+        }
+
+        false
+    }
+
     pub fn read_from_memory(&mut self, addr: u16, size: u16) -> Vec<u8> {
         let mut output = vec![];
         for curr in addr..(addr+size) {
@@ -341,30 +871,389 @@ impl<M: Addressable> Machine<M> {
         output
     }
 
-    pub fn step(&mut self) -> Result<State, String> {
+    // step executes a single instruction and returns the
+    // state of the machine along with the cycles it consumed
+    pub fn step(&mut self) -> Result<(State, u32), VmError> {
+        self.step_until(&HashSet::new())
+            .map(|(state, cycles, _)| (state, cycles))
+    }
+
+    // step_until is step stopping right after entering an interrupt
+    // whose handler is one of the `breakpoints`, without executing an
+    // instruction. Returns the amount of executed instructions as well
+    fn step_until(&mut self, breakpoints: &HashSet<u16>) -> Result<(State, u32, u64), VmError> {
+        let halt = self.registers[Register::FLAGS as usize] & 0b1 == 1;
+        if halt {
+            return Ok((State::Stop, 0, 0));
+        }
+
+        if let Some(journal) = self.journal.as_mut() {
+            journal.begin(self.registers, self.cycles);
+        }
+
+        // a failed step is recorded (and traced) as well, it may
+        // have changed FLAGS or the memory before failing
+        let before = self.registers;
+        let result = self.execute_step(breakpoints);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.commit();
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.finish(&before, &self.registers);
+        }
+        result
+    }
+
+    // step_block runs the basic block at PC, a straight-line run of
+    // instructions translated once and kept until its code is written.
+    // It returns the state after the last executed instruction and the
+    // cycles of the whole block. The machine ends up exactly as after
+    // the same instructions run through step: the block stops early
+    // when PC leaves it, an interrupt is taken or its code is written.
+    // The debugger, the journal and the tracer observe every single
+    // step, with any of them enabled this is the same as step
+    pub fn step_block(&mut self) -> Result<(State, u32), VmError> {
+        self.execute_block(u64::MAX, &HashSet::new())
+            .map(|(state, cycles, _)| (state, cycles))
+    }
+
+    // execute_block runs the block at PC executing at most `limit`
+    // instructions and stopping before any of the `breakpoints` past
+    // the first instruction. Returns the amount of executed instructions
+    // along with the state and the cycles
+    fn execute_block(
+        &mut self,
+        limit: u64,
+        breakpoints: &HashSet<u16>,
+    ) -> Result<(State, u32, u64), VmError> {
+        let single_step = |machine: &mut Self| machine.step_until(breakpoints);
+
+        if self.is_debug || self.journal.is_some() || self.tracer.is_some() {
+            return single_step(self);
+        }
+
         let halt = self.registers[Register::FLAGS as usize] & 0b1 == 1;
         if halt {
-            return Ok(State::Stop);
+            return Ok((State::Stop, 0, 0));
+        }
+
+        let pc = self.registers[Register::PC as usize];
+        let Some(block) = self.blocks.block(&self.memory, pc) else {
+            return single_step(self);
+        };
+        self.blocks.take_invalidated();
+
+        let mut total = 0;
+        let mut executed = 0;
+        for (idx, &(raw, inst)) in block.instructions.iter().enumerate() {
+            let pc = block.pc_of(idx);
+            if self.registers[Register::FLAGS as usize] & 0b1 == 1
+                || executed == limit
+                || (idx > 0 && breakpoints.contains(&pc))
+            {
+                break;
+            }
+
+            let cycles = self.poll_interrupt()?;
+            if self.registers[Register::PC as usize] != pc || self.blocks.take_invalidated() {
+                // the interrupt moved PC to its handler (or its push
+                // overwrote the block), the step goes on interpreted
+                if self.stops_at_handler(pc, cycles, breakpoints) {
+                    return Ok((State::Continue, total + cycles, executed));
+                }
+                let (state, cycles) = self.execute_fetched(cycles)?;
+                return Ok((state, total + cycles, executed + 1));
+            }
+
+            let (state, cycles) = self.execute_at(pc, raw, inst, cycles)?;
+            total += cycles;
+            executed += 1;
+
+            let is_next = self.registers[Register::PC as usize] == pc.wrapping_add(2);
+            if state != State::Continue || !is_next || self.blocks.take_invalidated() {
+                return Ok((state, total, executed));
+            }
+        }
+
+        Ok((State::Continue, total, executed))
+    }
+
+    // run executes instructions until the machine stops or one of the
+    // limits of `config` is reached, see RunConfig
+    pub fn run(&mut self, config: &RunConfig) -> StopReason {
+        let mut executed = 0;
+        let mut next_deadline_check = 0;
+
+        loop {
+            let pc = self.registers[Register::PC as usize];
+            if executed > 0 && config.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+
+            let remaining = match config.max_instructions {
+                Some(max) if executed >= max => return StopReason::BudgetExhausted,
+                Some(max) => max - executed,
+                None => u64::MAX,
+            };
+
+            if let Some(deadline) = config.deadline
+                && executed >= next_deadline_check
+            {
+                if Instant::now() >= deadline {
+                    return StopReason::DeadlineExceeded;
+                }
+                next_deadline_check = executed + DEADLINE_INTERVAL;
+            }
+
+            let result = if config.blocks {
+                self.execute_block(remaining, &config.breakpoints)
+            } else {
+                self.step_until(&config.breakpoints)
+            };
+
+            match result {
+                // only entering an interrupt executes nothing,
+                // its handler is a breakpoint
+                Ok((State::Continue, _, 0)) => {
+                    return StopReason::Breakpoint(self.registers[Register::PC as usize]);
+                }
+                Ok((State::Continue, _, n)) => executed += n,
+                Ok((State::Stop, ..)) => return StopReason::Halted,
+                Ok((State::Breakpoint(tag), ..)) => return StopReason::DebugTrap(tag),
+                Err(err) => return StopReason::Fault(err),
+            }
+        }
+    }
+
+    fn execute_step(&mut self, breakpoints: &HashSet<u16>) -> Result<(State, u32, u64), VmError> {
+        let pc = self.registers[Register::PC as usize];
+        let cycles = self.poll_interrupt()?;
+        if self.stops_at_handler(pc, cycles, breakpoints) {
+            return Ok((State::Continue, cycles, 0));
+        }
+
+        self.execute_fetched(cycles)
+            .map(|(state, cycles)| (state, cycles, 1))
+    }
+
+    // stops_at_handler tells if an interrupt taken at `pc` moved PC to
+    // one of the `breakpoints`, the `cycles` of the entry are accounted
+    // right away as no instruction follows it
+    fn stops_at_handler(&mut self, pc: u16, cycles: u32, breakpoints: &HashSet<u16>) -> bool {
+        let handler = self.registers[Register::PC as usize];
+        if handler == pc || !breakpoints.contains(&handler) {
+            return false;
+        }
+
+        self.cycles += cycles as u64;
+        self.memory.clock(self.cycles);
+        true
+    }
+
+    // poll_interrupt enters the handler of a pending interrupt,
+    // returning the cycles it took
+    fn poll_interrupt(&mut self) -> Result<u32, VmError> {
+        if self.is_flag_active(FLAG_INTERRUPT_ENABLE)
+            && let Some(line) = self.memory.take_interrupt()
+        {
+            let pc = self.registers[Register::PC as usize];
+            if self.enter_interrupt(line).map_err(|fault| fault.at(pc, 0))? {
+                return Ok(self.costs.interrupt);
+            }
         }
 
+        Ok(0)
+    }
+
+    // execute_fetched fetches and executes the instruction at PC,
+    // `cycles` were already consumed by the step
+    fn execute_fetched(&mut self, cycles: u32) -> Result<(State, u32), VmError> {
         let pc = self.registers[Register::PC as usize];
-        let raw = self.memory.read2(pc).unwrap();
+        let (raw, decoded) = self.fetch(pc)?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.fetched(pc, raw, decoded.ok());
+        }
+        let inst = decoded.map_err(|err| Fault::from(err).at(pc, raw))?;
 
-        let inst = Instruction::try_from(raw)?;
+        self.execute_at(pc, raw, inst, cycles)
+    }
 
+    fn execute_at(
+        &mut self,
+        pc: u16,
+        raw: u16,
+        inst: Instruction,
+        mut cycles: u32,
+    ) -> Result<(State, u32), VmError> {
         if self.is_debug {
             self.print_regs();
             print!("{:?} @ {}\r\n", inst, pc);
         }
 
+        cycles += self.costs.cost(&inst);
+
+        // user code may write FLAGS as long as it keeps the
+        // privileged bits, otherwise the write is undone
+        let written = match self.is_flag_active(FLAG_USER_MODE) {
+            true => written_register(&inst),
+            false => None,
+        };
+        let before = self.registers;
+        // user code can't run the kernel code, only enter it by a trap
+        let fetch_denied = self.is_flag_active(FLAG_USER_MODE)
+            && is_supervisor_memory(&self.supervisor_regions, pc, 2);
+        let result = match fetch_denied {
+            true => Err(Fault::PrivilegeViolation),
+            false => match self.execute(inst) {
+                Ok(_)
+                    if written == Some(Register::FLAGS)
+                        && (before[Register::FLAGS as usize]
+                            ^ self.registers[Register::FLAGS as usize])
+                            & PRIVILEGED_FLAGS
+                            != 0 =>
+                {
+                    self.registers = before;
+                    Err(Fault::PrivilegeViolation)
+                }
+                result => result,
+            },
+        };
+
+        let state = match result {
+            Err(Fault::DivisionByZero) => {
+                cycles += self.costs.interrupt;
+                self.division_by_zero_trap(pc, raw)?
+            }
+            Err(Fault::PrivilegeViolation) => {
+                cycles += self.costs.interrupt;
+                self.privilege_trap(pc, raw)?
+            }
+            result => result.map_err(|fault| fault.at(pc, raw))?,
+        };
+
+        self.cycles += cycles as u64;
+        self.memory.clock(self.cycles);
+        Ok((state, cycles))
+    }
+
+    // fetch reads and decodes the instruction at `pc`, going through
+    // the decode cache when it is enabled. Only aligned instructions
+    // that decode successfully are cached
+    fn fetch(&mut self, pc: u16) -> Result<(u16, Result<Instruction, DecodeError>), VmError> {
+        let slot = (pc >> 1) as usize;
+        let is_aligned = pc & 1 == 0;
+
+        if is_aligned
+            && let Some(Some((raw, inst))) = self.decode_cache.get(slot)
+        {
+            return Ok((*raw, Ok(*inst)));
+        }
+
+        let raw = self
+            .memory
+            .read2(pc)
+            .ok_or(VmError::FetchOutOfBounds { pc, raw: 0 })?;
+        let decoded = Instruction::try_from(raw);
+
+        if is_aligned
+            && self.decode_cache_enabled
+            && let Ok(inst) = decoded
+            && self.memory.is_cacheable(pc)
+            && self.memory.is_cacheable(pc + 1)
+        {
+            if self.decode_cache.is_empty() {
+                self.decode_cache = vec![None; DECODE_CACHE_SIZE];
+            }
+            self.decode_cache[slot] = Some((raw, inst));
+        }
+
+        Ok((raw, decoded))
+    }
+
+    // drops the cached instructions and blocks overlapping [addr, addr + len[
+    fn invalidate_code(&mut self, addr: u16, len: u16) {
+        if !self.decode_cache.is_empty() {
+            for offset in 0..len {
+                self.decode_cache[(addr.wrapping_add(offset) >> 1) as usize] = None;
+            }
+        }
+        self.blocks.invalidate(addr, len);
+    }
+
+    // division_by_zero_trap flags the fault to the guest, then either
+    // jumps to the configured trap handler or halts the machine
+    fn division_by_zero_trap(&mut self, pc: u16, raw: u16) -> Result<State, VmError> {
+        self.set_flags(1 << FLAG_DIV_BY_ZERO);
+
+        match self.div_trap_handler {
+            Some(handler) => {
+                self.enter_handler(handler, pc.wrapping_add(2))
+                    .map_err(|fault| fault.at(pc, raw))?;
+                Ok(State::Continue)
+            }
+            None => {
+                self.set_flags(0b1);
+                Err(VmError::DivisionByZero { pc, raw })
+            }
+        }
+    }
+
+    // privilege_trap enters the supervisor through the privilege
+    // vector, the handler gets the address of the faulting instruction
+    // as return address. Without a handler the machine stops
+    fn privilege_trap(&mut self, pc: u16, raw: u16) -> Result<State, VmError> {
+        let handler = self
+            .read_memory2(interrupts::PRIVILEGE_VECTOR)
+            .unwrap_or(0);
+        if handler == 0 {
+            return Err(VmError::PrivilegeViolation { pc, raw });
+        }
+
+        self.enter_handler(handler, pc)
+            .map_err(|fault| fault.at(pc, raw))?;
+        Ok(State::Continue)
+    }
+
+    fn execute(&mut self, inst: Instruction) -> Result<State, Fault> {
         match inst {
-            Instruction::Noop => {
-                self.registers[Register::PC as usize] += 2;
-                if self.is_debug {
-                    return Ok(State::Debug);
-                } else {
-                    return Ok(State::Continue);
+            // an all zero word is not an instruction, usually
+            // PC ran into memory that was never written
+            Instruction::Noop => return Err(Fault::IllegalOpcode),
+            Instruction::Breakpoint(tag) => {
+                self.advance_pc();
+                return Ok(State::Breakpoint(tag));
+            }
+            Instruction::Halt(code) => {
+                if self.is_flag_active(FLAG_USER_MODE) {
+                    return Err(Fault::PrivilegeViolation);
+                }
+                return Ok(self.exit(code as u16));
+            }
+            Instruction::Syscall(number) => {
+                if let Some(mut syscalls) = self.syscalls.take() {
+                    self.syscall_flags = self.registers[Register::FLAGS as usize];
+                    let action = syscalls.handle(number, self);
+                    self.syscalls = Some(syscalls);
+
+                    match action {
+                        SyscallAction::Done => {
+                            self.advance_pc();
+                            return Ok(State::Continue);
+                        }
+                        SyscallAction::Exit(code) => return Ok(self.exit(code)),
+                        SyscallAction::Unhandled => {}
+                    }
+                }
+
+                let handler = self.read_memory2(interrupts::SYSCALL_VECTOR).unwrap_or(0);
+                if handler == 0 {
+                    return Err(Fault::UnhandledSyscall);
                 }
+
+                let ret_addr = self.registers[Register::PC as usize].wrapping_add(2);
+                self.enter_handler(handler, ret_addr)?;
+                self.push(number as u16)?;
+                return Ok(State::Continue);
             }
             Instruction::Mov(dst_reg, reg, imm) => {
                 match (reg, imm) {
@@ -392,52 +1281,65 @@ impl<M: Addressable> Machine<M> {
             Instruction::Cpy(reg_src, reg_dst) => {
                 let src_addr = self.registers[reg_src as usize];
                 let dst_addr = self.registers[reg_dst as usize];
-                if !self.memory.copy(src_addr, dst_addr, 1) {
+                self.check_user_write(dst_addr, 1)?;
+                let copied = self
+                    .read_memory(src_addr)
+                    .is_some_and(|value| self.write_memory(dst_addr, value));
+                if !copied {
                     self.set_flags((0b1 << 2) | 0b1);
+                    return Err(Fault::ReadOnlyWrite(dst_addr));
                 }
             }
             Instruction::Arith(dst_reg, src_reg, imm, arith_op) => match (src_reg, imm) {
                 (Some(src), None) => {
-                    self.arithmetic_op(dst_reg, self.registers[src as usize], arith_op)
+                    self.arithmetic_op(dst_reg, self.registers[src as usize], arith_op)?
                 }
-                (None, Some(imm)) => self.arithmetic_op(dst_reg, imm, arith_op),
+                (None, Some(imm)) => self.arithmetic_op(dst_reg, imm, arith_op)?,
                 _ => unreachable!(),
             },
             Instruction::ArithRegReg(dst_reg, fst_reg, snd_reg, op ) => { 
-                self.arithmetic_op_reg_reg(dst_reg, fst_reg, snd_reg, op);
+                self.arithmetic_op_reg_reg(dst_reg, fst_reg, snd_reg, op)?;
             },
             Instruction::LdrStr(r0, addr_reg, is_str, shift) => {
-                let base = self.registers[addr_reg as usize];
-                let at = base + (shift as u16);
+                let at = self.effective_addr(addr_reg, shift)?;
 
                 if is_str {
                     let to_store = self.registers[r0 as usize];
-                    if !self.memory.write2(at, to_store) {
+                    self.check_user_write(at, 2)?;
+                    if !self.write_memory2(at, to_store) {
 
                         self.set_flags((0b1 << 2) | 0b1);
+                        return Err(Fault::ReadOnlyWrite(at));
                     }
                 } else {
-                    if let Some(value) = self.memory.read2(at) {
+                    if let Some(value) = self.read_memory2(at) {
                         self.registers[r0 as usize] = value;
                     }
                 }
             }
             Instruction::LdbStb(r0, addr_reg, is_str, shift) => {
-                let base = self.registers[addr_reg as usize];
-                let at = base + (shift as u16);
+                let at = self.effective_addr(addr_reg, shift)?;
 
                 if is_str {
                     let to_store: u8 = self.registers[r0 as usize] as u8;
-                    if !self.memory.write(at, to_store) {
+                    self.check_user_write(at, 1)?;
+                    if !self.write_memory(at, to_store) {
 
                         self.set_flags((0b1 << 2) | 0b1);
+                        return Err(Fault::ReadOnlyWrite(at));
                     }
                 } else {
-                    if let Some(value) = self.memory.read(at) {
+                    if let Some(value) = self.read_memory(at) {
                         self.registers[r0 as usize] = value as u16;
                     }
                 }
             }
+            Instruction::LdbSigned(r0, addr_reg, shift) => {
+                let at = self.effective_addr(addr_reg, shift)?;
+                if let Some(value) = self.read_memory(at) {
+                    self.registers[r0 as usize] = value as i8 as u16;
+                }
+            }
             Instruction::Jmp(opt_reg, opt_imm) => match (opt_reg, opt_imm) {
                 (Some(reg), None) => {
                     let addr = self.registers[reg as usize];
@@ -489,21 +1391,251 @@ impl<M: Addressable> Machine<M> {
                 let curr = self.registers[Register::PC as usize];
 
                 self.registers[Register::PC as usize] = addr;
-                self.registers[Register::M as usize] = curr + 2;
+                self.registers[Register::M as usize] = curr.wrapping_add(2);
 
                 return Ok(State::Continue);
             }
-            _ => {
-                return Err(format!("invalid instruction: {:?}", inst))
-            },
+            Instruction::Bitwise(dst_reg, opt_reg, opt_imm, op) => {
+                let rhs = match (opt_reg, opt_imm) {
+                    (Some(src_reg), None) => self.registers[src_reg as usize],
+                    (None, Some(imm)) => imm,
+                    _ => unreachable!(),
+                };
+
+                self.bitwise_op(dst_reg, rhs, op);
+            }
+            Instruction::Call(opt_reg, opt_imm) => {
+                let addr = match (opt_reg, opt_imm) {
+                    (Some(reg), None) => self.registers[reg as usize],
+                    (None, Some(imm)) => imm,
+                    _ => unreachable!(),
+                };
+
+                let ret_addr = self.registers[Register::PC as usize].wrapping_add(2);
+                self.push(ret_addr)?;
+                self.registers[Register::PC as usize] = addr;
+                return Ok(State::Continue);
+            }
+            Instruction::Ret => {
+                let ret_addr = self.pop()?;
+                self.registers[Register::PC as usize] = ret_addr;
+                return Ok(State::Continue);
+            }
+            Instruction::Rti => {
+                if self.is_flag_active(FLAG_USER_MODE) {
+                    return Err(Fault::PrivilegeViolation);
+                }
+
+                let ret_addr = self.pop()?;
+                let flags = self.pop()?;
+                // returning to user mode goes back to the user stack
+                if flags & (1 << FLAG_USER_MODE) != 0 {
+                    let user_sp = self.pop()?;
+                    self.registers[Register::SP as usize] = user_sp;
+                }
+                self.registers[Register::PC as usize] = ret_addr;
+                self.registers[Register::FLAGS as usize] = flags;
+                return Ok(State::Continue);
+            }
+            Instruction::Push(reg) => {
+                self.push(self.registers[reg as usize])?;
+            }
+            Instruction::Pop(reg) => {
+                let value = self.pop()?;
+                self.registers[reg as usize] = value;
+            }
+            Instruction::Enter(size) => {
+                self.push(self.registers[Register::BP as usize])?;
+
+                let sp = self.registers[Register::SP as usize];
+                let locals = self.checked_stack_sub(sp, size as u16)?;
+                self.registers[Register::BP as usize] = sp;
+                self.registers[Register::SP as usize] = locals;
+            }
+            Instruction::Leave => {
+                self.registers[Register::SP as usize] = self.registers[Register::BP as usize];
+                let bp = self.pop()?;
+                self.registers[Register::BP as usize] = bp;
+            }
         }
 
-        self.registers[Register::PC as usize] += 2;
+        self.advance_pc();
         Ok(State::Continue)
     }
 
-    fn set_flags(&mut self, flags: u16) {
-        self.registers[Register::FLAGS as usize] |= flags;
+    // enters the line handler, the PC of the interrupted
+    // instruction is the return address. Returns false when
+    // the line has no handler
+    fn enter_interrupt(&mut self, line: u8) -> Result<bool, Fault> {
+        let handler = self.read_memory2(interrupts::vector_addr(line))
+            .unwrap_or(0);
+
+        // there is no handler installed for the line
+        if handler == 0 {
+            return Ok(false);
+        }
+
+        self.enter_handler(handler, self.registers[Register::PC as usize])?;
+        Ok(true)
+    }
+
+    // saves FLAGS and `ret_addr` on the stack (to be restored by RTI),
+    // disables interrupts and jumps to `handler` in supervisor mode.
+    // Coming from user mode the frame goes on the kernel stack, after
+    // the user SP, so user code can't choose where it's written
+    fn enter_handler(&mut self, handler: u16, ret_addr: u16) -> Result<(), Fault> {
+        let flags = self.registers[Register::FLAGS as usize];
+        if flags & (1 << FLAG_USER_MODE) != 0 {
+            let user_sp = self.registers[Register::SP as usize];
+            let kernel_sp = self.read_memory2(interrupts::KERNEL_SP_VECTOR).unwrap_or(0);
+            if kernel_sp != 0 {
+                // the kernel stack may sit in the supervisor memory,
+                // a frame on the user stack is still checked
+                self.registers[Register::SP as usize] = kernel_sp;
+                self.registers[Register::FLAGS as usize] &= !(1 << FLAG_USER_MODE);
+            }
+            self.push(user_sp)?;
+        }
+
+        self.push(flags)?;
+        self.push(ret_addr)?;
+
+        self.registers[Register::FLAGS as usize] =
+            flags & !((1 << FLAG_INTERRUPT_ENABLE) | (1 << FLAG_USER_MODE));
+        self.registers[Register::PC as usize] = handler;
+        Ok(())
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), Fault> {
+        let sp = self.checked_stack_sub(self.registers[Register::SP as usize], 2)?;
+        self.check_user_write(sp, 2)?;
+        if !self.write_memory2(sp, value) {
+            return Err(Fault::ReadOnlyWrite(sp));
+        }
+
+        self.registers[Register::SP as usize] = sp;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, Fault> {
+        let sp = self.registers[Register::SP as usize];
+        let next_sp = match self.stack_region {
+            Some((_, base)) => sp
+                .checked_add(2)
+                .filter(|next_sp| *next_sp <= base)
+                .ok_or(Fault::StackUnderflow)?,
+            None => sp.wrapping_add(2),
+        };
+
+        let value = self.read_memory2(sp).ok_or(Fault::ReadOutOfBounds(sp))?;
+        self.registers[Register::SP as usize] = next_sp;
+        Ok(value)
+    }
+
+    // moves the stack pointer `sp` down by `n` bytes, checking
+    // it stays inside the stack region (when there is one)
+    fn checked_stack_sub(&self, sp: u16, n: u16) -> Result<u16, Fault> {
+        match self.stack_region {
+            Some((limit, _)) => sp
+                .checked_sub(n)
+                .filter(|next_sp| *next_sp >= limit)
+                .ok_or(Fault::StackOverflow),
+            None => Ok(sp.wrapping_sub(n)),
+        }
+    }
+
+    // user code can't write the vectors nor the kernel, it would
+    // install its own handler and get it run in supervisor mode
+    fn check_user_write(&mut self, addr: u16, len: u16) -> Result<(), Fault> {
+        if self.is_flag_active(FLAG_USER_MODE) && is_supervisor_memory(&self.supervisor_regions, addr, len) {
+            return Err(Fault::PrivilegeViolation);
+        }
+
+        Ok(())
+    }
+
+    // the guest memory accesses go through these helpers so the
+    // journal can keep the overwritten bytes and the tracer can
+    // record what was read and written
+    fn read_memory(&mut self, addr: u16) -> Option<u8> {
+        let value = self.memory.read(addr)?;
+        self.trace_access(addr, &[value], false);
+        Some(value)
+    }
+
+    fn read_memory2(&mut self, addr: u16) -> Option<u16> {
+        let value = self.memory.read2(addr)?;
+        self.trace_access(addr, &value.to_le_bytes(), false);
+        Some(value)
+    }
+
+    fn write_memory(&mut self, addr: u16, value: u8) -> bool {
+        self.journal_write(addr, 1);
+        let written = self.memory.write(addr, value);
+        if written {
+            self.invalidate_code(addr, 1);
+            self.trace_access(addr, &[value], true);
+        }
+        written
+    }
+
+    fn write_memory2(&mut self, addr: u16, value: u16) -> bool {
+        self.journal_write(addr, 2);
+        let written = self.memory.write2(addr, value);
+        if written {
+            self.invalidate_code(addr, 2);
+            self.trace_access(addr, &value.to_le_bytes(), true);
+        }
+        written
+    }
+
+    fn trace_access(&mut self, addr: u16, bytes: &[u8], is_write: bool) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            for (offset, value) in bytes.iter().enumerate() {
+                tracer.access(addr.wrapping_add(offset as u16), *value, is_write);
+            }
+        }
+    }
+
+    fn journal_write(&mut self, addr: u16, len: u16) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+
+        for offset in 0..len {
+            let at = addr.wrapping_add(offset);
+            if !self.memory.is_cacheable(at) {
+                continue;
+            }
+            if let Some(old) = self.memory.read(at) {
+                journal.record(at, old);
+            }
+        }
+    }
+
+    // exit sets the halt bit keeping the exit code,
+    // PC is left after the instruction that exited
+    fn exit(&mut self, code: u16) -> State {
+        self.exit_code = Some(code);
+        self.set_flags(0b1);
+        self.advance_pc();
+        State::Stop
+    }
+
+    fn advance_pc(&mut self) {
+        let pc = self.registers[Register::PC as usize];
+        self.registers[Register::PC as usize] = pc.wrapping_add(2);
+    }
+
+    // base address held by `addr_reg` plus the instruction offset
+    fn effective_addr(&self, addr_reg: Register, shift: u8) -> Result<u16, Fault> {
+        self.registers[addr_reg as usize]
+            .checked_add(shift as u16)
+            .ok_or(Fault::ArithmeticOverflow)
+    }
+
+    fn set_flags(&mut self, flags: u16) {
+        self.registers[Register::FLAGS as usize] |= flags;
     }
 
     fn remove_flags(&mut self, flags: u16) {
@@ -516,23 +1648,36 @@ impl<M: Addressable> Machine<M> {
         return extract_flag != 0;
     }
 
-    fn arithmetic_op(&mut self, dst_reg: Register, imm: u16, op: ArithmeticOp) {
+    fn arithmetic_op(&mut self, dst_reg: Register, imm: u16, op: ArithmeticOp) -> Result<(), Fault> {
         let lhs: u16 = self.registers[dst_reg as usize];
-        let result: u16 = match op {
-            ArithmeticOp::Add => lhs + imm,
-            ArithmeticOp::Sub => lhs - imm,
-            ArithmeticOp::Mul => lhs * imm,
+        let (result, carry, overflow) = match op {
+            ArithmeticOp::Add | ArithmeticOp::Sub | ArithmeticOp::Mul => {
+                self.wrapping_arith(lhs, imm, op)
+            }
             ArithmeticOp::Div => {
+                if imm == 0 {
+                    return Err(Fault::DivisionByZero);
+                }
+
                 let store_mod: bool = (self.registers[Register::FLAGS as usize] >> 1) & 0b1 == 1;
                 if store_mod {
-                    self.memory
-                        .write(self.registers[Register::SP as usize], (lhs % imm) as u8);
+                    let sp = self.registers[Register::SP as usize];
+                    self.check_user_write(sp, 1)?;
+                    self.write_memory(sp, (lhs % imm) as u8);
                 }
-                lhs / imm
+                (lhs / imm, false, false)
             }
-            _ => unreachable!(),
+            ArithmeticOp::SignedDiv | ArithmeticOp::SignedMod => {
+                if imm == 0 {
+                    return Err(Fault::DivisionByZero);
+                }
+
+                self.wrapping_arith(lhs, imm, op)
+            }
+            _ => return Err(Fault::IllegalOpcode),
         };
-        self.registers[dst_reg as usize] = result;
+        self.write_arith_result(dst_reg, result, carry, overflow);
+        Ok(())
     }
 
     fn arithmetic_op_reg_reg(
@@ -541,20 +1686,117 @@ impl<M: Addressable> Machine<M> {
         fst_reg: Register,
         snd_reg: Register,
         op: ArithmeticOp,
-    ) {
+    ) -> Result<(), Fault> {
         let lhs: u16 = self.registers[fst_reg as usize];
         let rhs: u16 = self.registers[snd_reg as usize];
 
-        let result: u16 = match op {
-            ArithmeticOp::Mod => lhs % rhs,
-            ArithmeticOp::Add => lhs + rhs,
-            ArithmeticOp::Sub => lhs - rhs,
-            ArithmeticOp::Mul => lhs * rhs,
-            ArithmeticOp::Div => lhs / rhs,
-            ArithmeticOp::Exp => lhs.pow(rhs as u32) as u16,
-            ArithmeticOp::Sqrt => (lhs as f32).sqrt() as u16,
+        if rhs == 0 && matches!(
+                op,
+                ArithmeticOp::Div
+                    | ArithmeticOp::Mod
+                    | ArithmeticOp::SignedDiv
+                    | ArithmeticOp::SignedMod
+            ) {
+            return Err(Fault::DivisionByZero);
+        }
+
+        let (result, carry, overflow) = self.wrapping_arith(lhs, rhs, op);
+        self.write_arith_result(dst_reg, result, carry, overflow);
+        Ok(())
+    }
+
+    // wrapping_arith computes `lhs op rhs` wrapping around the u16
+    // boundaries, it returns the result along with the carry (unsigned
+    // overflow, or borrow for Sub) and the overflow (signed overflow)
+    fn wrapping_arith(&self, lhs: u16, rhs: u16, op: ArithmeticOp) -> (u16, bool, bool) {
+        let (slhs, srhs) = (lhs as i16, rhs as i16);
+        match op {
+            ArithmeticOp::Add => {
+                let (result, carry) = lhs.overflowing_add(rhs);
+                (result, carry, slhs.overflowing_add(srhs).1)
+            }
+            ArithmeticOp::Adc => {
+                let carry_in = self.registers[Register::FLAGS as usize] >> FLAG_CARRY & 0b1;
+                let (partial, fst_carry) = lhs.overflowing_add(rhs);
+                let (result, snd_carry) = partial.overflowing_add(carry_in);
+                let (spartial, fst_overflow) = slhs.overflowing_add(srhs);
+                let snd_overflow = spartial.overflowing_add(carry_in as i16).1;
+                (result, fst_carry || snd_carry, fst_overflow != snd_overflow)
+            }
+            ArithmeticOp::Sub => {
+                let (result, borrow) = lhs.overflowing_sub(rhs);
+                (result, borrow, slhs.overflowing_sub(srhs).1)
+            }
+            ArithmeticOp::Mul => {
+                let (result, carry) = lhs.overflowing_mul(rhs);
+                (result, carry, slhs.overflowing_mul(srhs).1)
+            }
+            ArithmeticOp::Div => (lhs / rhs, false, false),
+            ArithmeticOp::Mod => (lhs % rhs, false, false),
+            ArithmeticOp::Exp => {
+                let (result, carry) = lhs.overflowing_pow(rhs as u32);
+                (result, carry, slhs.overflowing_pow(rhs as u32).1)
+            }
+            ArithmeticOp::Sqrt => ((lhs as f32).sqrt() as u16, false, false),
+            // -32768 / -1 is the only quotient that does not fit, it
+            // wraps back to -32768 and sets the overflow
+            ArithmeticOp::SignedDiv => {
+                let (result, overflow) = slhs.overflowing_div(srhs);
+                (result as u16, false, overflow)
+            }
+            ArithmeticOp::SignedMod => {
+                let (result, overflow) = slhs.overflowing_rem(srhs);
+                (result as u16, false, overflow)
+            }
+        }
+    }
+
+    // bitwise_op updates the zero and negative bits of FLAGS as the
+    // arithmetic operations do, shifts also set the carry with the
+    // last bit shifted out
+    fn bitwise_op(&mut self, dst_reg: Register, rhs: u16, op: BitwiseOp) {
+        let lhs = self.registers[dst_reg as usize];
+        let shift = rhs as u32;
+
+        let last_out = |bit: u32| shift > 0 && shift <= 16 && (lhs >> bit) & 0b1 == 1;
+        let (result, carry) = match op {
+            BitwiseOp::And => (lhs & rhs, false),
+            BitwiseOp::Or => (lhs | rhs, false),
+            BitwiseOp::Xor => (lhs ^ rhs, false),
+            BitwiseOp::Not => (!rhs, false),
+            BitwiseOp::ShiftLeft => (
+                lhs.checked_shl(shift).unwrap_or(0),
+                last_out(16u32.saturating_sub(shift)),
+            ),
+            BitwiseOp::ShiftRight => (
+                lhs.checked_shr(shift).unwrap_or(0),
+                last_out(shift.saturating_sub(1)),
+            ),
+            BitwiseOp::ShiftArith => (
+                (lhs as i16).checked_shr(shift).unwrap_or((lhs as i16) >> 15) as u16,
+                last_out(shift.saturating_sub(1)) || (shift > 16 && lhs >> 15 == 1),
+            ),
         };
-        self.registers[dst_reg as usize] = result
+
+        self.write_arith_result(dst_reg, result, carry, false);
+    }
+
+    // stores the result of an arithmetic operation and updates the
+    // carry, overflow, zero and negative bits of FLAGS. When FLAGS
+    // itself is the destination the written value is kept as is
+    fn write_arith_result(&mut self, dst_reg: Register, result: u16, carry: bool, overflow: bool) {
+        self.registers[dst_reg as usize] = result;
+        if dst_reg == Register::FLAGS {
+            return;
+        }
+
+        let status = ((carry as u16) << FLAG_CARRY)
+            | ((overflow as u16) << FLAG_OVERFLOW)
+            | (((result == 0) as u16) << FLAG_ZERO)
+            | ((result >> 15) << FLAG_NEGATIVE);
+
+        self.registers[Register::FLAGS as usize] &= !ARITH_FLAGS_MASK;
+        self.registers[Register::FLAGS as usize] |= status;
     }
 
     fn compare_op(&mut self, lhs: u16, rhs: u16, op: CompareOp) {
@@ -565,6 +1807,10 @@ impl<M: Addressable> Machine<M> {
             CompareOp::LessEq => lhs <= rhs,
             CompareOp::Greater => lhs > rhs,
             CompareOp::GreaterEq => lhs >= rhs,
+            CompareOp::SignedLess => (lhs as i16) < (rhs as i16),
+            CompareOp::SignedLessEq => (lhs as i16) <= (rhs as i16),
+            CompareOp::SignedGreater => (lhs as i16) > (rhs as i16),
+            CompareOp::SignedGreaterEq => (lhs as i16) >= (rhs as i16),
         };
 
         if is_true {
@@ -587,17 +1833,150 @@ impl<M: Addressable> Machine<M> {
     }
 }
 
+// the syscall handlers see the machine as the guest does
+impl<M: Addressable> SyscallContext for Machine<M> {
+    fn register(&self, reg: Register) -> u16 {
+        self.registers[reg as usize]
+    }
+
+    fn set_register(&mut self, reg: Register, value: u16) {
+        self.registers[reg as usize] = value;
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        self.read_memory(addr)
+    }
+
+    // the handler writes on behalf of the caller, a user mode
+    // caller can't reach the vectors nor the kernel through it
+    fn write(&mut self, addr: u16, value: u8) -> bool {
+        if self.syscall_flags & (1 << FLAG_USER_MODE) != 0
+            && is_supervisor_memory(&self.supervisor_regions, addr, 1)
+        {
+            return false;
+        }
+
+        self.write_memory(addr, value)
+    }
+}
+
+impl<M: Addressable + Snapshot> Machine<M> {
+    // snapshot captures the registers, the cycle count, the trap,
+    // stack and supervisor memory configuration, the exit code and the memory. The debug mode and the
+    // cycle costs are options of the host and are not stored
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.bytes(SNAPSHOT_MAGIC);
+        w.u16(SNAPSHOT_VERSION);
+
+        for value in self.registers {
+            w.u16(value);
+        }
+        w.u64(self.cycles);
+
+        w.u8(self.div_trap_handler.is_some() as u8);
+        w.u16(self.div_trap_handler.unwrap_or(0));
+
+        let (limit, base) = self.stack_region.unwrap_or((0, 0));
+        w.u8(self.stack_region.is_some() as u8);
+        w.u16(limit);
+        w.u16(base);
+
+        w.u8(self.exit_code.is_some() as u8);
+        w.u16(self.exit_code.unwrap_or(0));
+
+        w.u16(self.supervisor_regions.len() as u16);
+        for (start, end) in self.supervisor_regions.iter() {
+            w.u16(*start);
+            w.u16(*end);
+        }
+
+        self.memory.save(&mut w);
+        w.into_bytes()
+    }
+
+    // restore replaces the machine state with the one in `snapshot`,
+    // when the memory fails to restore it may be left partially restored
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let mut r = SnapshotReader::new(snapshot);
+        if r.bytes(SNAPSHOT_MAGIC.len()).ok() != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::InvalidMagic);
+        }
+
+        let version = r.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut registers = [0; 8];
+        for value in registers.iter_mut() {
+            *value = r.u16()?;
+        }
+        let cycles = r.u64()?;
+
+        let (has_handler, handler) = (r.u8()? == 1, r.u16()?);
+        let (has_stack, limit, base) = (r.u8()? == 1, r.u16()?, r.u16()?);
+        let (has_exit_code, exit_code) = (r.u8()? == 1, r.u16()?);
+        let mut supervisor_regions = vec![];
+        for _ in 0..r.u16()? {
+            supervisor_regions.push((r.u16()?, r.u16()?));
+        }
+
+        self.memory.restore(&mut r)?;
+        if !r.is_empty() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+
+        self.registers = registers;
+        self.cycles = cycles;
+        self.div_trap_handler = has_handler.then_some(handler);
+        self.stack_region = has_stack.then_some((limit, base));
+        self.exit_code = has_exit_code.then_some(exit_code);
+        self.supervisor_regions = supervisor_regions;
+        self.memory.clock(cycles);
+
+        // the recorded steps, decoded instructions and
+        // blocks belong to the previous state
+        if let Some(journal) = self.journal.as_mut() {
+            journal.clear();
+        }
+        self.decode_cache = vec![];
+        self.blocks.clear();
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::usize;
 
+    use std::{
+        cell::RefCell,
+        collections::{HashMap, HashSet},
+        fs,
+        path::Path,
+        rc::Rc,
+        time::Instant,
+    };
+
     use crate::{
-        machine::{Register, State},
+        asm::{assemble, assemble_sources, encode_instruction, image_words, parse_assembly_line},
+        devices::{Device, clock::CycleCounter},
+        interrupts::{self, IrqLine},
+        machine::{Register, State, VmError},
         memory::{Addressable, LinearMemory},
+        mmio::MemoryWithDevices,
+        run::{RunConfig, StopReason},
         rv16asm,
+        snapshot::SnapshotError,
+        syscall::{StdSyscalls, SyscallAction, SyscallContext, SyscallHandler},
+        timing::CycleCosts,
+        trace::{
+            MemAccess, TraceError, TraceEvent, TraceFilter, TraceSink, TraceWriter, first_divergence, read_trace,
+        },
     };
 
-    use super::Machine;
+    use super::{DECODE_CACHE_SIZE, Instruction, Machine};
 
     #[test]
     fn invalid_instruction_opcode() {
@@ -607,7 +1986,56 @@ mod test {
 
         let result = machine.step();
         assert!(result.is_err());
-        assert_eq!(Err(String::from("invalid instruction: Noop")), result);
+        assert_eq!(Err(VmError::IllegalOpcode { pc: 0, raw: 0 }), result);
+    }
+
+    #[test]
+    fn step_reports_faults_with_pc_and_raw_word() {
+        let program = rv16asm! {
+            "MOV A, #1",
+            "DIV A, #0",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+
+        let mut machine = Machine::new(mem);
+        machine.step().unwrap();
+        assert_eq!(
+            Err(VmError::DivisionByZero { pc: 2, raw: program[1] }),
+            machine.step()
+        );
+        assert_eq!(Ok((State::Stop, 0)), machine.step());
+
+        // 0111 | A | 110 | 0 | #0, 0b110 is not a comparison
+        let mut mem = LinearMemory::new(1024);
+        mem.write2(0, 0x0307);
+        let mut machine = Machine::new(mem);
+        assert_eq!(
+            Err(VmError::IllegalOpcode { pc: 0, raw: 0x0307 }),
+            machine.step()
+        );
+
+        let program = rv16asm! {
+            "MOV A, #63",
+            "LDR B, [A #31]",
+        };
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.step().unwrap();
+        machine.set_register(Register::A, u16::MAX);
+        assert!(matches!(
+            machine.step(),
+            Err(VmError::ArithmeticOverflow { pc: 2, .. })
+        ));
+
+        let mut machine = Machine::new(LinearMemory::new(1024));
+        machine.set_register(Register::PC, 1024);
+        assert_eq!(
+            Err(VmError::FetchOutOfBounds { pc: 1024, raw: 0 }),
+            machine.step()
+        );
     }
 
     #[test]
@@ -758,7 +2186,7 @@ mod test {
         assert!(mem.write_program(&program));
 
         let mut machine = Machine::new(mem);
-        while let Ok(State::Continue) = machine.step() {
+        while let Ok((State::Continue, _)) = machine.step() {
             machine.print_regs();
         }
         assert_eq!(machine.registers[Register::A as usize], 10);
@@ -777,11 +2205,47 @@ mod test {
         assert!(mem.write_program(&program));
 
         let mut machine = Machine::new(mem);
-        while let Ok(State::Continue) = machine.step() {
+        while let Ok((State::Continue, _)) = machine.step() {
             machine.print_regs();
         }
 
-        assert_eq!(machine.registers[Register::FLAGS as usize], 5) 
+        assert_eq!(machine.registers[Register::FLAGS as usize], 5);
+        assert!(matches!(
+            machine.step(),
+            Ok((State::Stop, _))
+        ));
+    }
+
+    #[test]
+    fn only_the_code_of_the_image_is_read_only() {
+        let code = "
+        start:
+            LI A, buffer
+            MOV B, #7
+            STB B, A
+            LI A, start
+            STB B, A
+            ADD FLAGS, #1
+        buffer:
+            .space #4
+        ";
+
+        let image = assemble_sources(&[("data.s", code)], &[]).unwrap();
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_image(&image.bytes, &image.code));
+        let mut machine = Machine::new(mem);
+        // each LI of a label takes 3 instructions
+        for _ in 0..8 {
+            machine.step().unwrap();
+        }
+
+        // the zeros of `.space` are a writable buffer, the code is not
+        assert_eq!(machine.memory.read(20), Some(7));
+        assert!(matches!(
+            machine.step(),
+            Err(VmError::ReadOnlyWrite { pc: 16, addr: 0, .. })
+        ));
+        assert_eq!(machine.memory.read(0), Some(image.bytes[0]));
     }
 
     #[test]
@@ -825,7 +2289,7 @@ mod test {
         assert!(mem.write_program(&program));
 
         let mut machine = Machine::new(mem);
-        while let Ok(State::Continue) = machine.step() {}
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
 
         assert_eq!(machine.registers[Register::A as usize], 40_320);
     }
@@ -855,10 +2319,11 @@ mod test {
         let mut machine = Machine::new(mem);
 
         machine.set_register(Register::PC, 6);
+        machine.set_register(Register::SP, 1024);
 
-        while let Ok(State::Continue) = machine.step() {}
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
 
-        assert_eq!(Ok(State::Stop), machine.step());
+        assert_eq!(Ok((State::Stop, 0)), machine.step());
         assert_eq!(machine.registers[Register::A as usize], 3);
         assert_eq!(machine.registers[Register::B as usize], 2);
         assert_eq!(machine.registers[Register::C as usize], 55);
@@ -902,7 +2367,7 @@ mod test {
         let mut machine = Machine::new(mem);
         machine.set_register(Register::SP, 1024);
 
-        while let Ok(State::Continue) = machine.step() {}
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
 
         machine.print_regs();
 
@@ -932,7 +2397,7 @@ mod test {
         let mut machine = Machine::new(mem);
         machine.set_register(Register::SP, 100);
 
-        while let Ok(State::Continue) = machine.step() {}
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
 
         machine.print_regs();
 
@@ -979,10 +2444,1453 @@ mod test {
         let mut machine = Machine::new(mem);
         machine.set_register(Register::SP, 100);
 
-        while let Ok(State::Continue) = machine.step() {
+        while let Ok((State::Continue, _)) = machine.step() {
         }
         assert_eq!(machine.registers[Register::B as usize], 10);
 
         machine.print_regs();
     }
+
+    #[test]
+    fn dispatch_interrupt_to_vector_and_return() {
+        let program = rv16asm! {
+            "MOV A, #0",
+            "ADD A, #1",
+            "JMP #2",
+
+            // irq 2 handler
+            "MOV B, #7",
+            "RTI",
+        };
+
+        let mut mem = LinearMemory::new(1 << 16);
+        assert!(mem.write_program(&program));
+        assert!(mem.write2(interrupts::vector_addr(2), 6));
+
+        let mem = MemoryWithDevices::new(mem);
+        let irq = mem.irq_line(2).unwrap();
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 0xE000);
+
+        machine.step().unwrap();
+        machine.step().unwrap();
+
+        // interrupts are disabled, the line stays pending
+        irq.raise();
+        machine.step().unwrap();
+        assert_eq!(machine.registers[Register::PC as usize], 2);
+        assert_eq!(machine.registers[Register::B as usize], 0);
+
+        machine.set_register(Register::FLAGS, 1 << 4);
+        machine.step().unwrap();
+
+        // the handler first instruction was executed
+        assert_eq!(machine.registers[Register::B as usize], 7);
+        assert_eq!(machine.registers[Register::PC as usize], 8);
+        assert_eq!(machine.registers[Register::SP as usize], 0xE000 - 4);
+        assert_eq!(machine.registers[Register::FLAGS as usize], 0);
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers[Register::PC as usize], 2);
+        assert_eq!(machine.registers[Register::SP as usize], 0xE000);
+        assert_eq!(machine.registers[Register::FLAGS as usize], 1 << 4);
+        assert_eq!(machine.registers[Register::A as usize], 1);
+    }
+
+    #[test]
+    fn arithmetic_wraps_and_sets_status_flags() {
+        let carry = 1 << 5;
+        let overflow = 1 << 6;
+        let zero = 1 << 7;
+        let negative = 1 << 8;
+
+        let run = |program: Vec<u16>, a: u16, b: u16| -> Machine<LinearMemory> {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::A, a);
+            machine.set_register(Register::B, b);
+            for _ in 0..program.len() {
+                machine.step().unwrap();
+            }
+            machine
+        };
+
+        let machine = run(rv16asm! { "ADD A, #1" }, u16::MAX, 0);
+        assert_eq!(machine.registers[Register::A as usize], 0);
+        assert_eq!(machine.registers[Register::FLAGS as usize], carry | zero);
+
+        let machine = run(rv16asm! { "SUB A, #1" }, 0, 0);
+        assert_eq!(machine.registers[Register::A as usize], u16::MAX);
+        assert_eq!(machine.registers[Register::FLAGS as usize], carry | negative);
+
+        let machine = run(rv16asm! { "ADD A, B" }, 0x7FFF, 1);
+        assert_eq!(machine.registers[Register::A as usize], 0x8000);
+        assert_eq!(machine.registers[Register::FLAGS as usize], overflow | negative);
+
+        let machine = run(rv16asm! { "MUL A, #2" }, 0x8000, 0);
+        assert_eq!(machine.registers[Register::A as usize], 0);
+        assert_eq!(machine.registers[Register::FLAGS as usize], carry | overflow | zero);
+
+        // the status bits are cleared by the next operation
+        let machine = run(rv16asm! { "ADD A, #1", "ADD A, #1" }, u16::MAX, 0);
+        assert_eq!(machine.registers[Register::A as usize], 1);
+        assert_eq!(machine.registers[Register::FLAGS as usize], 0);
+
+        // 0x0001_FFFF + 0x0000_0001 = 0x0002_0000, A:C holds the low words
+        // and B:M holds the high words
+        let mut machine = run(rv16asm! { "ADDR A, A, C", "ADCR B, B, M" }, 0, 0);
+        machine.set_register(Register::PC, 0);
+        machine.set_register(Register::A, 0xFFFF);
+        machine.set_register(Register::B, 0x0001);
+        machine.set_register(Register::C, 0x0001);
+        machine.set_register(Register::M, 0x0000);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers[Register::A as usize], 0);
+        assert_eq!(machine.registers[Register::B as usize], 2);
+    }
+
+    #[test]
+    fn division_by_zero_halts_or_traps() {
+        let program = rv16asm! {
+            "MOV A, #10",
+            "MOV B, #0",
+            "DIVR C, A, B",
+            "MOV C, #1",
+            "ADD FLAGS, #1",
+
+            // division trap handler
+            "MOV M, #5",
+            "RTI",
+        };
+
+        // without a handler the machine halts
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+
+        assert_eq!(
+            machine.run(&RunConfig::with_budget(10_000)),
+            StopReason::Fault(VmError::DivisionByZero { pc: 4, raw: program[2] })
+        );
+        assert_eq!(machine.registers[Register::PC as usize], 4);
+        assert_eq!(machine.registers[Register::FLAGS as usize], (1 << 9) | 1);
+        assert_eq!(Ok((State::Stop, 0)), machine.step());
+
+        // with a handler the guest recovers
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_div_trap_handler(10);
+
+        let mut steps = 0;
+        while let Ok((State::Continue, _)) = machine.step() {
+            steps += 1;
+        }
+        assert_eq!(steps, 7);
+        assert_eq!(machine.registers[Register::M as usize], 5);
+        assert_eq!(machine.registers[Register::C as usize], 1);
+        assert_eq!(machine.registers[Register::SP as usize], 1024);
+        assert_eq!(machine.registers[Register::FLAGS as usize], (1 << 9) | 1);
+    }
+
+    #[test]
+    fn push_pop_and_stack_frames() {
+        let program = rv16asm! {
+            "MOV A, #10",
+            "MOV B, #20",
+            "PUSH A",
+            "PUSH B",
+            "POP A",
+            "POP B",
+
+            "ENTER #4",
+            "MOV C, #3",
+            "STR C, [SP #2]",
+            "LEAVE",
+            "ADD FLAGS, #1",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_register(Register::BP, 900);
+        machine.set_stack_region(1000, 1024);
+
+        for _ in 0..6 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[Register::A as usize], 20);
+        assert_eq!(machine.registers[Register::B as usize], 10);
+        assert_eq!(machine.registers[Register::SP as usize], 1024);
+
+        // ENTER saves BP and allocates 4 bytes
+        machine.step().unwrap();
+        assert_eq!(machine.registers[Register::BP as usize], 1022);
+        assert_eq!(machine.registers[Register::SP as usize], 1018);
+        assert_eq!(machine.memory.read2(1022), Some(900));
+
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+        assert_eq!(machine.memory.read2(1020), Some(3));
+        assert_eq!(machine.registers[Register::BP as usize], 900);
+        assert_eq!(machine.registers[Register::SP as usize], 1024);
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow() {
+        let program = rv16asm! {
+            "PUSH A",
+            "PUSH A",
+            "PUSH A",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_stack_region(1020, 1024);
+
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(
+            Err(VmError::StackOverflow { pc: 4, raw: program[2] }),
+            machine.step()
+        );
+        assert_eq!(machine.registers[Register::SP as usize], 1020);
+
+        let program = rv16asm! {
+            "POP A",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_stack_region(1020, 1024);
+        assert_eq!(
+            Err(VmError::StackUnderflow { pc: 0, raw: program[0] }),
+            machine.step()
+        );
+
+        // 1111 | 0001 | 8, there is no register 8
+        let mut mem = LinearMemory::new(1024);
+        mem.write2(0, 0x081f);
+        let mut machine = Machine::new(mem);
+        assert_eq!(
+            Err(VmError::InvalidRegister { pc: 0, raw: 0x081f }),
+            machine.step()
+        );
+    }
+
+    #[test]
+    fn link_call_uses_register_m() {
+        let program = rv16asm! {
+            "ADD A, B",
+            "LRET",
+
+            "MOV A, #1",
+            "MOV B, #2",
+            "LCALL #0",
+            "ADD FLAGS, #1"
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::PC, 4);
+
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+
+        assert_eq!(machine.registers[Register::A as usize], 3);
+        assert_eq!(machine.registers[Register::M as usize], 10);
+        assert_eq!(machine.registers[Register::SP as usize], 0);
+    }
+
+    #[test]
+    fn recursive_calls() {
+        // sum(n) = n + sum(n - 1), sum(0) = 0
+        let program = rv16asm! {
+            "MOV A, #10",
+            "CALL #8",
+            "ADD FLAGS, #1",
+            "ADD FLAGS, #1",
+
+            // sum:
+            "EQ A, #0",
+            "CJP #22",
+            "PUSH A",
+            "SUB A, #1",
+            "CALL #8",
+            "POP C",
+            "ADD B, C",
+            "RET",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_stack_region(512, 1024);
+
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+
+        assert_eq!(machine.registers[Register::B as usize], 55);
+        assert_eq!(machine.registers[Register::PC as usize], 6);
+        assert_eq!(machine.registers[Register::SP as usize], 1024);
+    }
+
+    #[test]
+    fn bitwise_and_shift_operations() {
+        let run = |line: &str, a: u16, b: u16| -> Machine<LinearMemory> {
+            let inst = parse_assembly_line(line, &HashMap::new()).unwrap();
+
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&[encode_instruction(&inst)]));
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::A, a);
+            machine.set_register(Register::B, b);
+            machine.step().unwrap();
+            machine
+        };
+
+        let a = |machine: Machine<LinearMemory>| machine.registers[Register::A as usize];
+        let flags = |machine: &Machine<LinearMemory>| machine.registers[Register::FLAGS as usize];
+
+        assert_eq!(a(run("AND A, B", 0b1100, 0b1010)), 0b1000);
+        assert_eq!(a(run("AND A, #1", 0b1011, 0)), 0b1);
+        assert_eq!(a(run("OR A, B", 0b1100, 0b1010)), 0b1110);
+        assert_eq!(a(run("OR A, #3", 0xF000, 0)), 0xF003);
+        assert_eq!(a(run("XOR A, B", 0b1100, 0b1010)), 0b0110);
+        assert_eq!(a(run("XOR A, #31", 0b10101, 0)), 0b01010);
+        assert_eq!(a(run("NOT A", 0x00FF, 0)), 0xFF00);
+        assert_eq!(a(run("NOT A, B", 0, 0x0F0F)), 0xF0F0);
+        assert_eq!(a(run("SHL A, #4", 0x0F01, 0)), 0xF010);
+        assert_eq!(a(run("SHL A, B", 1, 15)), 0x8000);
+        assert_eq!(a(run("SHL A, B", 1, 16)), 0);
+        assert_eq!(a(run("SHR A, #4", 0xF010, 0)), 0x0F01);
+        assert_eq!(a(run("SAR A, #4", 0xF010, 0)), 0xFF01);
+        assert_eq!(a(run("SAR A, B", 0x8000, 20)), 0xFFFF);
+        assert_eq!(a(run("SAR A, #1", 0x7FFE, 0)), 0x3FFF);
+
+        // zero, negative and the bit shifted out into the carry
+        let machine = run("AND A, #0", 0xFFFF, 0);
+        assert_eq!(flags(&machine), 1 << 7);
+        let machine = run("SHL A, #1", 0x8001, 0);
+        assert_eq!(flags(&machine), 1 << 5);
+        let machine = run("SHR A, #1", 0x8001, 0);
+        assert_eq!(flags(&machine), 1 << 5);
+        let machine = run("NOT A", 0, 0);
+        assert_eq!(flags(&machine), 1 << 8);
+    }
+
+    #[test]
+    fn count_cycles() {
+        let program = rv16asm! {
+            "MOV A, #5",
+            "LDR B, C",
+            "CALL #8",
+            "OR FLAGS, #1",
+            "RET",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+
+        let mut consumed = vec![];
+        while let Ok((State::Continue, cycles)) = machine.step() {
+            consumed.push(cycles);
+        }
+
+        // MOV, LDR, CALL, RET, OR
+        assert_eq!(consumed, vec![1, 2, 3, 3, 1]);
+        assert_eq!(machine.cycles(), 10);
+        assert_eq!(machine.step(), Ok((State::Stop, 0)));
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.set_cycle_costs(CycleCosts::uniform(1));
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+        assert_eq!(machine.cycles(), 5);
+
+        // the guest reads the counter through MMIO
+        let program = rv16asm! {
+            "MOV A, #1",
+            "MOV B, #200",
+            "LDR C, B",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut mem = MemoryWithDevices::new(mem);
+        mem.register_device(CycleCounter::new(), 200, 8).unwrap();
+
+        let mut machine = Machine::new(mem);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+
+        assert_eq!(machine.registers[Register::C as usize], 2);
+        assert_eq!(machine.cycles(), 4);
+        assert_eq!(machine.memory.read2(200), Some(4));
+    }
+
+    // a single byte register that opts into snapshots
+    struct Latch(u8);
+
+    impl Device for Latch {
+        fn read(&self, _offset: usize) -> u8 {
+            self.0
+        }
+
+        fn write(&mut self, _offset: usize, value: u8) {
+            self.0 = value;
+        }
+
+        fn save_state(&self) -> Option<Vec<u8>> {
+            Some(vec![self.0])
+        }
+
+        fn restore_state(&mut self, state: &[u8]) -> Result<(), String> {
+            self.0 = state[0];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let program = rv16asm! {
+            "MOV A, #0",
+            "MOV B, #200",
+            "ADD A, #1",
+            "STB A, B",
+            "LT A, #10",
+            "CJP #4",
+            "OR FLAGS, #1",
+        };
+
+        let new_machine = || {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+            let mut mem = MemoryWithDevices::new(mem);
+            mem.register_device(Latch(0), 300, 1).unwrap();
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::SP, 1024);
+            machine.set_stack_region(512, 1024);
+            machine
+        };
+
+        let mut machine = new_machine();
+        machine.add_supervisor_region(600, 700);
+        for _ in 0..12 {
+            machine.step().unwrap();
+        }
+        assert!(machine.memory.write(300, 42));
+        let snapshot = machine.snapshot();
+
+        assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+        assert_eq!(machine.registers[Register::A as usize], 10);
+
+        let mut restored = new_machine();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.registers[Register::A as usize], 3);
+        assert_eq!(restored.memory.read(200), Some(3));
+        assert_eq!(restored.memory.read(300), Some(42));
+        assert_eq!(restored.stack_region, Some((512, 1024)));
+        assert_eq!(restored.supervisor_regions, vec![(600, 700)]);
+
+        // the program region is still read-only
+        assert!(!restored.memory.write(0, 0xFF));
+
+        assert_eq!(restored.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+        assert_eq!(restored.registers, machine.registers);
+        assert_eq!(restored.cycles(), machine.cycles());
+        assert_eq!(restored.snapshot(), machine.snapshot());
+
+        let mut other = new_machine();
+        assert_eq!(other.restore(b"RV16"), Err(SnapshotError::InvalidMagic));
+        assert_eq!(
+            other.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+
+        let mut bumped = snapshot.clone();
+        bumped[8] = 2;
+        assert_eq!(other.restore(&bumped), Err(SnapshotError::UnsupportedVersion(2)));
+
+        let mut without_device = Machine::new(MemoryWithDevices::new(LinearMemory::new(1024)));
+        assert!(matches!(
+            without_device.restore(&snapshot),
+            Err(SnapshotError::Device { addr: 300, .. })
+        ));
+    }
+
+    #[test]
+    fn step_back_through_the_journal() {
+        let program = rv16asm! {
+            "MOV A, #0",
+            "MOV B, #200",
+            "ADD A, #1",
+            "STR A, B",
+            "PUSH A",
+            "LT A, #5",
+            "CJP #4",
+            "CALL #18",
+            "OR FLAGS, #1",
+            "RET",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 1024);
+        machine.enable_journal(64);
+
+        let initial = machine.snapshot();
+        let mut states = vec![];
+        loop {
+            states.push(machine.snapshot());
+            if let Ok((State::Stop, _)) = machine.step() {
+                break;
+            }
+        }
+        assert_eq!(machine.memory.read2(200), Some(5));
+        assert_eq!(machine.history_len(), states.len() - 1);
+
+        // every step back goes through the same states in reverse
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert!(machine.step_back());
+            assert_eq!(machine.snapshot(), state);
+        }
+        assert!(!machine.step_back());
+        assert_eq!(machine.snapshot(), initial);
+
+        // the machine stops right before the 3rd ADD, going
+        // back to PC 4 reaches the 2nd one
+        for _ in 0..12 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[Register::PC as usize], 4);
+        assert!(machine.run_back_to(4));
+        assert_eq!(machine.registers[Register::A as usize], 1);
+        assert!(!machine.run_back_to(16));
+        assert_eq!(machine.registers[Register::PC as usize], 0);
+
+        // only the last steps are kept
+        machine.enable_journal(3);
+        for _ in 0..10 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.history_len(), 3);
+        assert!(machine.step_back() && machine.step_back() && machine.step_back());
+        assert!(!machine.step_back());
+        assert_eq!(machine.registers[Register::PC as usize], 4);
+    }
+
+    // counts the writes it gets
+    struct WriteCounter(Rc<RefCell<usize>>);
+
+    impl Device for WriteCounter {
+        fn read(&self, _offset: usize) -> u8 {
+            0
+        }
+
+        fn write(&mut self, _offset: usize, _value: u8) {
+            *self.0.borrow_mut() += 1;
+        }
+    }
+
+    #[test]
+    fn step_back_skips_the_devices() {
+        let program = rv16asm! {
+            "MOV A, #7",
+            "MOV B, #200",
+            "STB A, B",
+            "MOV B, #250",
+            "STR A, B",
+            "OR FLAGS, #1",
+        };
+
+        let writes = Rc::new(RefCell::new(0));
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut mem = MemoryWithDevices::new(mem);
+        mem.register_device(WriteCounter(writes.clone()), 200, 1).unwrap();
+
+        let mut machine = Machine::new(mem);
+        machine.enable_journal(64);
+        for _ in 0..5 {
+            machine.step().unwrap();
+        }
+        assert_eq!(*writes.borrow(), 1);
+        assert_eq!(machine.memory.read2(250), Some(7));
+
+        // the plain memory is restored, the device is not written again
+        while machine.step_back() {}
+        assert_eq!(*writes.borrow(), 1);
+        assert_eq!(machine.memory.read2(250), Some(0));
+        assert_eq!(machine.registers[Register::PC as usize], 0);
+    }
+
+    #[test]
+    fn trace_steps_and_find_divergence() {
+        let program = rv16asm! {
+            "MOV A, #7",
+            "MOV B, #200",
+            "STB A, B",
+            "LDB C, B",
+            "PUSH C",
+            "OR FLAGS, #1",
+        };
+
+        let run = |a: u16| {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::SP, 1024);
+
+            let events = Rc::new(RefCell::new(Vec::<TraceEvent>::new()));
+            machine.set_trace_sink(Box::new(events.clone()));
+            machine.step().unwrap();
+            machine.set_register(Register::A, a);
+            assert_eq!(machine.run(&RunConfig::with_budget(10_000)), StopReason::Halted);
+            events.take()
+        };
+
+        let events = run(7);
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[1].step, 1);
+        assert_eq!(events[1].pc, 2);
+        assert_eq!(events[1].raw, program[1]);
+        assert_eq!(events[1].registers, vec![(Register::B, 0, 200), (Register::PC, 2, 4)]);
+
+        let write = |addr, value| MemAccess { addr, value, is_write: true };
+        assert_eq!(events[2].memory, vec![write(200, 7)]);
+        assert_eq!(events[3].memory, vec![MemAccess { addr: 200, value: 7, is_write: false }]);
+        assert_eq!(events[4].instruction, Some(Instruction::Push(Register::C)));
+        assert_eq!(events[4].memory, vec![write(1022, 7), write(1023, 0)]);
+
+        // round trip through the trace file format
+        let mut file = vec![];
+        let mut writer = TraceWriter::new(&mut file);
+        for event in events.iter() {
+            writer.record(event);
+        }
+        assert!(writer.error().is_none());
+        assert_eq!(read_trace(file.as_slice()).unwrap(), events);
+
+        // the decode errors are told apart
+        let header = file[..10].to_vec();
+        assert!(matches!(read_trace(&file[..4]), Err(TraceError::InvalidMagic)));
+        let mut newer = file.clone();
+        newer[8] = 2;
+        assert!(matches!(read_trace(newer.as_slice()), Err(TraceError::UnsupportedVersion(2))));
+        assert!(matches!(read_trace(&file[..file.len() - 1]), Err(TraceError::Truncated)));
+
+        // a record with a memory access of kind 2, then one with register 9
+        let mut bad = header.clone();
+        bad.extend([0; 8]);
+        bad.extend([0, 0, 0, 0, 0, 1, 0, 2, 0, 0, 7]);
+        assert!(matches!(read_trace(bad.as_slice()), Err(TraceError::InvalidAccess(2))));
+        let mut bad = header;
+        bad.extend([0; 8]);
+        bad.extend([0, 0, 0, 0, 1, 9]);
+        assert!(matches!(read_trace(bad.as_slice()), Err(TraceError::InvalidRegister(9))));
+
+        let filter = TraceFilter {
+            mnemonic: Some("ldb".to_string()),
+            ..TraceFilter::default()
+        };
+        let found: Vec<u64> = events.iter().filter(|e| filter.matches(e)).map(|e| e.step).collect();
+        assert_eq!(found, vec![3]);
+
+        let filter = TraceFilter {
+            addr: Some(200),
+            from_step: Some(3),
+            ..TraceFilter::default()
+        };
+        assert_eq!(events.iter().filter(|e| filter.matches(e)).count(), 1);
+
+        // changing A after the first step shows up at the STB
+        assert_eq!(first_divergence(&events, &run(7)), None);
+        assert_eq!(first_divergence(&events, &run(9)), Some(2));
+        assert_eq!(first_divergence(&events, &events[..4]), Some(4));
+    }
+
+    #[test]
+    fn decode_cache_is_invalidated_by_writes() {
+        let program = rv16asm! {
+            "MOV B, #100",
+            "JMP #100",
+            "MOV M, #200",
+            "LDR C, M",
+            "STR C, B",
+            "JMP #100",
+        };
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+
+        let run = |cached: bool| {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+            // code outside of the read-only program region
+            assert!(mem.write2(100, encode("ADD A, #1")));
+            assert!(mem.write2(102, encode("JMP #4")));
+            assert!(mem.write2(200, encode("ADD A, #5")));
+
+            let mut machine = Machine::new_debug(mem, false);
+            machine.set_decode_cache(cached);
+            // the cache is only allocated by the first fetch
+            assert!(machine.decode_cache.is_empty());
+            for _ in 0..9 {
+                machine.step().unwrap();
+            }
+            assert_eq!(machine.decode_cache.len(), if cached { DECODE_CACHE_SIZE } else { 0 });
+            machine.registers[Register::A as usize]
+        };
+
+        // the 2nd run at address 100 executes the stored ADD A, #5
+        assert_eq!(run(true), 6);
+        assert_eq!(run(false), 6);
+    }
+
+    #[test]
+    fn signed_operations() {
+        let run = |line: &str, a: u16, b: u16| -> Machine<LinearMemory> {
+            let inst = parse_assembly_line(line, &HashMap::new()).unwrap();
+
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&[encode_instruction(&inst)]));
+            mem.write(512, 0xFE);
+            mem.write(513, 0x7F);
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::A, a);
+            machine.set_register(Register::B, b);
+            machine.step().unwrap();
+            machine
+        };
+
+        let a = |machine: &Machine<LinearMemory>| machine.registers[Register::A as usize];
+        let is_true = |machine: Machine<LinearMemory>| {
+            (machine.registers[Register::FLAGS as usize] >> 3) & 0b1 == 1
+        };
+
+        // -1 is below 1 when signed, above it when unsigned
+        assert!(is_true(run("SLT A, B", 0xFFFF, 1)));
+        assert!(!is_true(run("LT A, B", 0xFFFF, 1)));
+        assert!(is_true(run("SLTE A, #-3", 0xFFFD, 0)));
+        assert!(!is_true(run("SLT A, #-3", 0xFFFD, 0)));
+        assert!(is_true(run("SGT A, #-16", 0, 0)));
+        assert!(is_true(run("SGTE A, B", 0x7FFF, 0x8000)));
+
+        assert_eq!(a(&run("SDIV A, #-2", 7, 0)), (-3i16) as u16);
+        assert_eq!(a(&run("SDIV A, B", (-7i16) as u16, 2)), (-3i16) as u16);
+        assert_eq!(a(&run("SMOD A, B", (-7i16) as u16, 2)), (-1i16) as u16);
+        assert_eq!(a(&run("SMOD A, #-2", 7, 0)), 1);
+
+        // the only quotient that does not fit wraps and overflows
+        let machine = run("SDIV A, B", 0x8000, 0xFFFF);
+        assert_eq!(a(&machine), 0x8000);
+        assert_eq!(machine.registers[Register::FLAGS as usize], (1 << 6) | (1 << 8));
+
+        assert_eq!(a(&run("LDBS A, B", 0, 512)), 0xFFFE);
+        assert_eq!(a(&run("LDBS A, [B #1]", 0, 512)), 0x007F);
+        assert_eq!(a(&run("LDB A, B", 0, 512)), 0x00FE);
+
+        let inst = parse_assembly_line("SDIV A, B", &HashMap::new()).unwrap();
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&[encode_instruction(&inst)]));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::A, 5);
+        assert_eq!(
+            machine.step(),
+            Err(VmError::DivisionByZero { pc: 0, raw: encode_instruction(&inst) })
+        );
+    }
+
+    // runs the machine until it stops, returning the last
+    // result and the amount of step/step_block calls
+    fn run_to_end<M: Addressable>(
+        machine: &mut Machine<M>,
+        blocks: bool,
+    ) -> (Result<State, VmError>, usize) {
+        for calls in 1..1_000_000 {
+            let result = if blocks { machine.step_block() } else { machine.step() };
+            match result {
+                Ok((State::Continue, _)) => continue,
+                result => return (result.map(|(state, _)| state), calls),
+            }
+        }
+        panic!("the program did not stop");
+    }
+
+    #[test]
+    fn run_stops_at_budget_breakpoints_and_deadline() {
+        let program = rv16asm! {
+            "MOV A, #0",
+            "ADD A, #1",
+            "JMP #2",
+        };
+
+        for blocks in [false, true] {
+            let new_machine = || {
+                let mut mem = LinearMemory::new(1024);
+                assert!(mem.write_program(&program));
+                Machine::new(mem)
+            };
+
+            // MOV and then 4 and a half iterations
+            let mut machine = new_machine();
+            let config = RunConfig {
+                blocks,
+                ..RunConfig::with_budget(10)
+            };
+            assert_eq!(machine.run(&config), StopReason::BudgetExhausted);
+            assert_eq!(machine.registers[Register::A as usize], 5);
+            assert_eq!(machine.registers[Register::PC as usize], 4);
+
+            // a run that starts at the breakpoint executes it
+            let mut machine = new_machine();
+            let config = RunConfig {
+                breakpoints: HashSet::from([4]),
+                blocks,
+                ..RunConfig::default()
+            };
+            assert_eq!(machine.run(&config), StopReason::Breakpoint(4));
+            assert_eq!(machine.registers[Register::A as usize], 1);
+            assert_eq!(machine.run(&config), StopReason::Breakpoint(4));
+            assert_eq!(machine.registers[Register::A as usize], 2);
+
+            let mut machine = new_machine();
+            let config = RunConfig {
+                deadline: Some(Instant::now()),
+                blocks,
+                ..RunConfig::default()
+            };
+            assert_eq!(machine.run(&config), StopReason::DeadlineExceeded);
+            assert_eq!(machine.cycles(), 0);
+        }
+
+        let program = rv16asm! { "DBG #7", "ADD FLAGS, #1" };
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+
+        let mut machine = Machine::new(mem);
+        assert_eq!(machine.run(&RunConfig::default()), StopReason::DebugTrap(7));
+        assert_eq!(machine.run(&RunConfig::default()), StopReason::Halted);
+    }
+
+    #[test]
+    fn breakpoint_instruction() {
+        let program = rv16asm! {
+            "MOV A, #1",
+            "DBG",
+            "ADD A, #1",
+            "DBG #200",
+            "ADD FLAGS, #1",
+        };
+
+        for is_debug in [false, true] {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+            let mut machine = Machine::new_debug(mem, is_debug);
+
+            assert_eq!(machine.step(), Ok((State::Continue, 1)));
+            assert_eq!(machine.step(), Ok((State::Breakpoint(0), 1)));
+            assert_eq!(machine.registers[Register::PC as usize], 4);
+            assert_eq!(machine.step(), Ok((State::Continue, 1)));
+            assert_eq!(machine.step(), Ok((State::Breakpoint(200), 1)));
+            assert_eq!(machine.registers[Register::A as usize], 2);
+            assert_eq!(machine.step(), Ok((State::Continue, 1)));
+            assert_eq!(machine.step(), Ok((State::Stop, 0)));
+        }
+
+        // an all zero word is never a breakpoint
+        let mut machine = Machine::new_debug(LinearMemory::new(1024), true);
+        assert_eq!(machine.step(), Err(VmError::IllegalOpcode { pc: 0, raw: 0 }));
+    }
+
+    #[test]
+    fn user_mode_syscalls_and_privilege_faults() {
+        let program = rv16asm! {
+            // kernel: RTI into user code with FLAGS = user
+            // mode, on the user stack at 1024
+            "MOV A, #4",
+            "SHL A, #8",
+            "PUSH A",
+            "MOV B, #1",
+            "MSL B, [#0 #7]",
+            "MSL B, [#0 #3]",
+            "PUSH B",
+            "MOV A, #26",
+            "PUSH A",
+            "RTI",
+
+            // syscall handler, the number is on top of the stack
+            "POP A",
+            "RTI",
+
+            // privilege violation handler
+            "ADD FLAGS, #1",
+
+            // user code
+            "ADD C, #1",
+            "OR FLAGS, #2",
+            "SYSCALL #9",
+            "ADD FLAGS, #1",
+        };
+
+        let mut mem = LinearMemory::new(1 << 16);
+        assert!(mem.write_program(&program));
+        assert!(mem.write2(interrupts::SYSCALL_VECTOR, 20));
+        assert!(mem.write2(interrupts::PRIVILEGE_VECTOR, 24));
+        assert!(mem.write2(interrupts::KERNEL_SP_VECTOR, 0xE000));
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::SP, 0xE000);
+        assert_eq!(machine.run(&RunConfig::with_budget(100)), StopReason::Halted);
+
+        assert_eq!(machine.registers[Register::A as usize], 9);
+        assert_eq!(machine.registers[Register::C as usize], 1);
+        // the handler runs in supervisor mode
+        assert_eq!(machine.registers[Register::FLAGS as usize] & (1 << 10), 0);
+
+        // the violation trap returns to the faulting instruction,
+        // the user FLAGS were kept without the halt bit and the user
+        // SP is back to 1024 after RTI restored it from the syscall
+        let sp = machine.registers[Register::SP as usize];
+        assert_eq!(sp, 0xE000 - 6);
+        assert_eq!(machine.memory.read2(sp), Some(32));
+        assert_eq!(machine.memory.read2(sp + 2), Some((1 << 10) | 0b10));
+        assert_eq!(machine.memory.read2(sp + 4), Some(1024));
+
+        // without handlers user code faults, leaving FLAGS and SP untouched
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+        for line in ["ADD FLAGS, #1", "POP FLAGS", "RTI", "SYSCALL #1"] {
+            let raw = encode(line);
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_program(&[raw]));
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::FLAGS, 1 << 10);
+            machine.set_register(Register::SP, 0x1000);
+
+            let expected = match line {
+                "SYSCALL #1" => VmError::UnhandledSyscall { pc: 0, raw },
+                _ => VmError::PrivilegeViolation { pc: 0, raw },
+            };
+            assert_eq!(machine.step(), Err(expected));
+            assert_eq!(machine.registers[Register::FLAGS as usize], 1 << 10);
+            assert_eq!(machine.registers[Register::SP as usize], 0x1000);
+        }
+    }
+
+    #[test]
+    fn user_mode_can_not_write_the_vectors() {
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+        let user_machine = |line| {
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_program(&[encode(line)]));
+            assert!(mem.write2(interrupts::PRIVILEGE_VECTOR, 0x100));
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::FLAGS, 1 << 10);
+            machine.set_register(Register::SP, 0x1000);
+            machine.set_register(Register::A, interrupts::SYSCALL_VECTOR);
+            machine.set_register(Register::B, 0x40);
+            machine
+        };
+
+        // overwriting a vector would run user code in supervisor mode,
+        // the write traps into the privilege handler instead
+        for line in ["STR B, A", "STB B, A", "CPY B, A"] {
+            let mut machine = user_machine(line);
+            assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+            assert_eq!(machine.memory.read2(interrupts::SYSCALL_VECTOR), Some(0));
+            assert_eq!(machine.registers[Register::PC as usize], 0x100);
+            assert_eq!(machine.registers[Register::FLAGS as usize] & (1 << 10), 0);
+        }
+
+        // and so does pushing into them
+        let mut machine = user_machine("PUSH B");
+        machine.set_register(Register::SP, 0xF000);
+        assert!(machine.memory.write2(interrupts::PRIVILEGE_VECTOR, 0));
+        assert_eq!(
+            machine.step(),
+            Err(VmError::PrivilegeViolation { pc: 0, raw: encode("PUSH B") })
+        );
+        assert_eq!(machine.memory.read2(0xEFFE), Some(0));
+
+        // the supervisor writes them freely
+        let mut machine = user_machine("STR B, A");
+        machine.set_register(Register::FLAGS, 0);
+        assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+        assert_eq!(machine.memory.read2(interrupts::SYSCALL_VECTOR), Some(0x40));
+    }
+
+    #[test]
+    fn user_mode_can_not_write_nor_run_the_kernel() {
+        let code = "
+            LI A, syscall
+            MOV B, #0
+            STR B, A
+            SYSCALL #1
+        privilege:
+            ADD FLAGS, #1
+        syscall:
+            POP B
+            MOV C, #1
+            RTI
+        ";
+
+        let user_machine = |line: &str| {
+            // nothing is read-only, only the supervisor region keeps
+            // user code away from the kernel
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_image(&assemble("kernel.s", code).unwrap(), &[]));
+            assert!(mem.write2(interrupts::SYSCALL_VECTOR, 14));
+            assert!(mem.write2(interrupts::KERNEL_SP_VECTOR, 0x2000));
+            assert!(mem.write2(0x100, encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap())));
+
+            let mut machine = Machine::new(mem);
+            machine.add_supervisor_region(12, 19);
+            machine.add_supervisor_region(0x1F00, 0x1FFF);
+            machine.set_register(Register::FLAGS, 1 << 10);
+            machine.set_register(Register::SP, 0x1000);
+            machine
+        };
+
+        // overwriting the body of the syscall handler faults
+        let mut machine = user_machine("RET");
+        let handler = machine.memory.read2(14);
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        assert!(matches!(
+            machine.step(),
+            Err(VmError::PrivilegeViolation { pc: 8, .. })
+        ));
+        assert_eq!(machine.memory.read2(14), handler);
+
+        // with the privilege handler installed it takes the trap
+        let mut machine = user_machine("RET");
+        assert!(machine.memory.write2(interrupts::PRIVILEGE_VECTOR, 12));
+        assert_eq!(machine.run(&RunConfig::with_budget(100)), StopReason::Halted);
+        assert_eq!(machine.memory.read2(14), handler);
+        assert_eq!(machine.memory.read2(0x2000 - 6), Some(8));
+
+        // user code can't jump into the kernel nor write its stack
+        for line in ["JMP #16", "STR B, A"] {
+            let mut machine = user_machine(line);
+            machine.set_register(Register::PC, 0x100);
+            machine.set_register(Register::A, 0x1FF0);
+            let raw = machine.memory.read2(0x100).unwrap();
+            let pc = if line == "JMP #16" { 16 } else { 0x100 };
+            if line == "JMP #16" {
+                machine.step().unwrap();
+            }
+            assert_eq!(machine.step(), Err(VmError::PrivilegeViolation {
+                pc,
+                raw: if pc == 16 { machine.memory.read2(16).unwrap() } else { raw },
+            }));
+            assert_eq!(machine.registers[Register::C as usize], 0);
+            assert_eq!(machine.memory.read2(0x1FF0), Some(0));
+        }
+
+        // the syscall enters the handler, that runs in supervisor mode
+        let mut machine = user_machine("RET");
+        machine.set_register(Register::PC, 10);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[Register::C as usize], 1);
+    }
+
+    #[test]
+    fn user_mode_traps_switch_to_the_kernel_stack() {
+        let code = "
+            MOV A, #5
+            CALL double
+            MOV SP, #0x23
+            SYSCALL #3
+        double:
+            ENTER #2
+            ADD A, A
+            LEAVE
+            RET
+        ";
+
+        let mut mem = LinearMemory::new(1 << 16);
+        assert!(mem.write_program(&image_words(&assemble("user.s", code).unwrap())));
+        assert!(mem.write2(interrupts::SYSCALL_VECTOR, 0x400));
+        assert!(mem.write2(interrupts::KERNEL_SP_VECTOR, 0x2000));
+
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::FLAGS, 1 << 10);
+        machine.set_register(Register::SP, 0x1000);
+
+        // a user mode function with a stack frame returns normally
+        for _ in 0..6 {
+            assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+        }
+        assert_eq!(machine.registers[Register::A as usize], 10);
+        assert_eq!(machine.registers[Register::PC as usize], 4);
+        assert_eq!(machine.registers[Register::SP as usize], 0x1000);
+
+        // user code can point SP anywhere, the trap frame is still
+        // pushed onto the kernel stack: user SP, FLAGS, return
+        // address and the syscall number
+        machine.step().unwrap();
+        assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+        assert_eq!(machine.registers[Register::PC as usize], 0x400);
+        assert_eq!(machine.registers[Register::SP as usize], 0x2000 - 8);
+        assert_eq!(machine.memory.read2(0x2000 - 2), Some(0x23));
+        assert_eq!(machine.memory.read2(0x2000 - 4), Some(1 << 10));
+        assert_eq!(machine.memory.read2(0x2000 - 6), Some(8));
+        assert_eq!(machine.memory.read2(0x2000 - 8), Some(3));
+        assert_eq!(machine.memory.read2(0x22), Some(0));
+
+        // RTI back to user mode restores the user SP
+        let rti = encode_instruction(&Instruction::Rti);
+        let pop = encode_instruction(&parse_assembly_line("POP B", &HashMap::new()).unwrap());
+        assert!(machine.memory.write2(0x400, pop));
+        assert!(machine.memory.write2(0x402, rti));
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers[Register::PC as usize], 8);
+        assert_eq!(machine.registers[Register::SP as usize], 0x23);
+        assert_eq!(machine.registers[Register::FLAGS as usize], 1 << 10);
+    }
+
+    #[test]
+    fn host_syscalls() {
+        let program = rv16asm! {
+            "MOV A, #200",
+            "SYSCALL #1",
+            "MOV A, #255",
+            "SYSCALL #2",
+            "MOV A, #220",
+            "MOV B, #4",
+            "SYSCALL #3",
+            "MOV C, A",
+            "MOV A, #220",
+            "SYSCALL #1",
+            "MOV A, #220",
+            "SYSCALL #3",
+            "MOV B, A",
+            "MOV A, #3",
+            "SYSCALL #0",
+            "ADD C, #1",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        for (offset, byte) in b"hi \0".iter().enumerate() {
+            assert!(mem.write(200 + offset as u16, *byte));
+        }
+
+        let syscalls = Rc::new(RefCell::new(StdSyscalls::new(&b"hello\n"[..], vec![])));
+        let mut machine = Machine::new(mem);
+        machine.set_syscall_handler(Box::new(syscalls.clone()));
+        assert_eq!(machine.run(&RunConfig::with_budget(100)), StopReason::Halted);
+
+        // the line was truncated to the 4 bytes buffer, NUL included
+        assert_eq!(syscalls.borrow().output(), b"hi 255hel");
+        assert_eq!(machine.registers[Register::C as usize], 3);
+        // the second read hit the end of the input
+        assert_eq!(machine.registers[Register::B as usize], 0xFFFF);
+        assert_eq!(machine.exit_code(), Some(3));
+        assert_eq!(machine.registers[Register::PC as usize], 30);
+
+        // the numbers a handler leaves unhandled trap into the guest
+        struct OnlyTwo;
+        impl SyscallHandler for OnlyTwo {
+            fn handle(&mut self, number: u8, ctx: &mut dyn SyscallContext) -> SyscallAction {
+                if number != 2 {
+                    return SyscallAction::Unhandled;
+                }
+                ctx.set_register(Register::B, 42);
+                SyscallAction::Done
+            }
+        }
+
+        let program = rv16asm! {
+            "SYSCALL #2",
+            "SYSCALL #5",
+            "ADD FLAGS, #1",
+            "POP A",
+            "RTI",
+        };
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_syscall_handler(Box::new(OnlyTwo));
+        machine.set_register(Register::SP, 1024);
+        assert_eq!(
+            machine.run(&RunConfig::with_budget(100)),
+            StopReason::Fault(VmError::UnhandledSyscall { pc: 2, raw: program[1] })
+        );
+        assert_eq!(machine.registers[Register::B as usize], 42);
+
+        let mut mem = LinearMemory::new(1 << 16);
+        assert!(mem.write_program(&program));
+        assert!(mem.write2(interrupts::SYSCALL_VECTOR, 6));
+        let mut machine = Machine::new(mem);
+        machine.set_syscall_handler(Box::new(OnlyTwo));
+        machine.set_register(Register::SP, 0xE000);
+        assert_eq!(machine.run(&RunConfig::with_budget(100)), StopReason::Halted);
+        assert_eq!(machine.registers[Register::A as usize], 5);
+        assert_eq!(machine.exit_code(), None);
+
+        // a user mode caller can't read a line into the vectors, the
+        // supervisor can
+        let program = rv16asm! { "SYSCALL #3" };
+        for (flags, expected) in [(1 << 10, 0), (0, 0x4241)] {
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_program(&program));
+            let mut machine = Machine::new(mem);
+            machine.set_syscall_handler(Box::new(StdSyscalls::new(&b"AB\n"[..], vec![])));
+            machine.set_register(Register::FLAGS, flags);
+            machine.set_register(Register::A, interrupts::SYSCALL_VECTOR);
+            machine.set_register(Register::B, 3);
+
+            assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+            assert_eq!(machine.memory.read2(interrupts::SYSCALL_VECTOR), Some(expected));
+            assert_eq!(machine.registers[Register::A as usize], if flags == 0 { 2 } else { 0xFFFF });
+        }
+
+        // the copy stops at a read-only span crossing the buffer
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        assert!(mem.as_read_only(100, 2));
+        let mut machine = Machine::new(mem);
+        machine.set_syscall_handler(Box::new(StdSyscalls::new(&b"hello\n"[..], vec![])));
+        machine.set_register(Register::A, 98);
+        machine.set_register(Register::B, 8);
+
+        assert_eq!(machine.step().map(|(state, _)| state), Ok(State::Continue));
+        assert_eq!(machine.registers[Register::A as usize], 0xFFFF);
+        assert_eq!(machine.read_from_memory(98, 6), vec![b'h', b'e', 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn halt_with_exit_code() {
+        let program = rv16asm! {
+            "MOV A, #1",
+            "HALT #3",
+            "ADD A, #1",
+        };
+
+        for blocks in [false, true] {
+            let mut mem = LinearMemory::new(1024);
+            assert!(mem.write_program(&program));
+            let mut machine = Machine::new(mem);
+            assert_eq!(machine.exit_code(), None);
+
+            let config = RunConfig { blocks, ..RunConfig::default() };
+            assert_eq!(machine.run(&config), StopReason::Halted);
+            assert_eq!(machine.exit_code(), Some(3));
+            assert_eq!(machine.registers[Register::A as usize], 1);
+            assert_eq!(machine.registers[Register::PC as usize], 4);
+            assert_eq!(machine.step(), Ok((State::Stop, 0)));
+
+            // the exit code is part of the snapshot
+            let mut restored = Machine::new(LinearMemory::new(1024));
+            restored.restore(&machine.snapshot()).unwrap();
+            assert_eq!(restored.exit_code(), Some(3));
+        }
+
+        // halting through FLAGS leaves no exit code
+        let program = rv16asm! { "ADD FLAGS, #1" };
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        assert_eq!(machine.run(&RunConfig::default()), StopReason::Halted);
+        assert_eq!(machine.exit_code(), None);
+
+        // user code can't halt the machine
+        let program = rv16asm! { "HALT #1" };
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        let mut machine = Machine::new(mem);
+        machine.set_register(Register::FLAGS, 1 << 10);
+        assert_eq!(
+            machine.step(),
+            Err(VmError::PrivilegeViolation { pc: 0, raw: program[0] })
+        );
+        assert_eq!(machine.exit_code(), None);
+    }
+
+    #[test]
+    fn block_engine_matches_the_interpreter() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let mut paths: Vec<_> = fs::read_dir(testdata)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "s"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let source = fs::read_to_string(&path).unwrap();
+            let program = image_words(&assemble(&path.to_string_lossy(), &source).unwrap());
+
+            let run = |blocks: bool| {
+                let mut mem = LinearMemory::new(1 << 16);
+                assert!(mem.write_program(&program));
+
+                let mut machine = Machine::new(mem);
+                machine.set_register(Register::SP, 0xFFFF);
+                let (result, calls) = run_to_end(&mut machine, blocks);
+                let memory = machine.read_from_memory(0, 0xFFFF);
+                (result, machine.registers, machine.cycles(), memory, calls)
+            };
+
+            let (result, registers, cycles, memory, steps) = run(false);
+            let (block_result, block_registers, block_cycles, block_memory, calls) = run(true);
+
+            assert_eq!(result, block_result, "{:?}", path);
+            assert_eq!(registers, block_registers, "{:?}", path);
+            assert_eq!(cycles, block_cycles, "{:?}", path);
+            assert!(memory == block_memory, "{:?}: memory differs", path);
+            assert!(calls <= steps, "{:?}", path);
+        }
+    }
+
+    // raises its line every `every` cycles
+    struct Ticker {
+        irq: IrqLine,
+        every: u64,
+        next: u64,
+    }
+
+    impl Device for Ticker {
+        fn read(&self, _offset: usize) -> u8 {
+            0
+        }
+
+        fn write(&mut self, _offset: usize, _value: u8) {}
+
+        fn clock(&mut self, cycles: u64) {
+            if cycles >= self.next {
+                self.irq.raise();
+                self.next += self.every;
+            }
+        }
+    }
+
+    #[test]
+    fn block_engine_handles_interrupts_and_self_modifying_code() {
+        let program = rv16asm! {
+            "OR FLAGS, #16",
+            "MOV B, #106",
+            "MOV M, #200",
+            "LDR C, M",
+            "JMP #100",
+        };
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+
+        let run = |blocks: bool| {
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_program(&program));
+            // the loop rewrites its own instruction at 106
+            assert!(mem.write2(100, encode("ADD A, #1")));
+            assert!(mem.write2(102, encode("STR C, B")));
+            assert!(mem.write2(104, encode("ADD A, #1")));
+            assert!(mem.write2(106, encode("ADD A, #2")));
+            assert!(mem.write2(108, encode("LT A, #30")));
+            assert!(mem.write2(110, encode("CJP #100")));
+            assert!(mem.write2(112, encode("OR FLAGS, #1")));
+            assert!(mem.write2(200, encode("ADD A, #5")));
+
+            // irq 1 counts its calls in BP
+            assert!(mem.write2(interrupts::vector_addr(1), 150));
+            assert!(mem.write2(150, encode("ADD BP, #1")));
+            assert!(mem.write2(152, encode("RTI")));
+
+            let mut mem = MemoryWithDevices::new(mem);
+            let irq = mem.irq_line(1).unwrap();
+            mem.register_device(Ticker { irq, every: 25, next: 25 }, 0xF000, 1).unwrap();
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::SP, 0xE000);
+            let (result, _) = run_to_end(&mut machine, blocks);
+            (result, machine.registers, machine.cycles(), machine.read_from_memory(0, 0xFFFF))
+        };
+
+        let (result, registers, cycles, memory) = run(false);
+        assert_eq!(result, Ok(State::Stop));
+        // STR rewrites 106 before it runs, so even the first
+        // iteration executes ADD A, #5 instead of ADD A, #2
+        assert_eq!(registers[Register::A as usize], 35);
+        assert!(registers[Register::BP as usize] > 0);
+
+        let (block_result, block_registers, block_cycles, block_memory) = run(true);
+        assert_eq!(result, block_result);
+        assert_eq!(registers, block_registers);
+        assert_eq!(cycles, block_cycles);
+        assert!(memory == block_memory);
+    }
+
+    #[test]
+    fn run_stops_at_a_breakpoint_on_an_interrupt_handler() {
+        let program = rv16asm! {
+            "OR FLAGS, #16",
+            "ADD A, #1",
+            "JMP #2",
+        };
+        let encode = |line| encode_instruction(&parse_assembly_line(line, &HashMap::new()).unwrap());
+
+        for blocks in [false, true] {
+            let mut mem = LinearMemory::new(1 << 16);
+            assert!(mem.write_program(&program));
+            assert!(mem.write2(interrupts::vector_addr(1), 150));
+            assert!(mem.write2(150, encode("ADD BP, #1")));
+            assert!(mem.write2(152, encode("RTI")));
+
+            let mut mem = MemoryWithDevices::new(mem);
+            let irq = mem.irq_line(1).unwrap();
+            mem.register_device(Ticker { irq, every: 25, next: 25 }, 0xF000, 1).unwrap();
+
+            let mut machine = Machine::new(mem);
+            machine.set_register(Register::SP, 0xE000);
+            let config = RunConfig {
+                blocks,
+                breakpoints: HashSet::from([150]),
+                ..RunConfig::with_budget(10_000)
+            };
+
+            // the machine stops once in the handler, before its first
+            // instruction, with the interrupted PC on the stack
+            assert_eq!(machine.run(&config), StopReason::Breakpoint(150));
+            assert_eq!(machine.registers[Register::BP as usize], 0);
+            assert_eq!(machine.registers[Register::SP as usize], 0xE000 - 4);
+            let interrupted = machine.memory.read2(0xE000 - 4).unwrap();
+            assert!(interrupted == 2 || interrupted == 4);
+
+            // resuming runs the handler up to the next interrupt
+            let cycles = machine.cycles();
+            assert!(cycles >= 25);
+            assert_eq!(machine.run(&config), StopReason::Breakpoint(150));
+            assert_eq!(machine.registers[Register::BP as usize], 1);
+            assert!(machine.cycles() >= cycles + 25);
+        }
+    }
 }
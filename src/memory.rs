@@ -1,3 +1,5 @@
+use crate::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+
 // Addressable is a trait that defines
 // any implementation over a memory where
 // the values can have an address.
@@ -32,9 +34,26 @@ pub trait Addressable {
     fn read(&self, addr: u16) -> Option<u8>;
     fn write(&mut self, addr: u16, value: u8) -> bool;
 
+    /// take_interrupt returns the next pending IRQ line, memories
+    /// without devices never raise interrupts
+    fn take_interrupt(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// clock informs the memory (and its devices) of the total
+    /// amount of cycles consumed by the machine
+    fn clock(&mut self, _cycles: u64) {}
+
+    /// is_cacheable tells if the value at `addr` only changes through
+    /// writes of the machine, so the instruction there can be cached
+    fn is_cacheable(&self, _addr: u16) -> bool {
+        true
+    }
+
     fn read2(&self, addr: u16) -> Option<u16> {
         self.read(addr).and_then(|lo| {
-            self.read(addr + 1)
+            // a u16 can't start at the very last address
+            self.read(addr.checked_add(1)?)
                 .map(|hi| (lo as u16) | ((hi as u16) << 8))
         })
     }
@@ -43,7 +62,10 @@ pub trait Addressable {
         let lo = (value & 0x00ff) as u8;
         let hi = ((value & 0xff00) >> 8) as u8;
 
-        self.write(addr, lo) && self.write(addr + 1, hi)
+        match addr.checked_add(1) {
+            Some(hi_addr) => self.write(addr, lo) && self.write(hi_addr, hi),
+            None => false,
+        }
     }
 
     /// copy places the values at [from ... from + n[
@@ -83,13 +105,44 @@ impl LinearMemory {
     // write program stores the set of instructions and mark
     // the region as read-only
     pub fn write_program(&mut self, program: &[u16]) -> bool {
+        // the length of the region must fit in a u16
+        if program.len() * 2 > u16::MAX as usize {
+            return false;
+        }
+
         for (idx, inst) in program.iter().enumerate() {
             if !self.write2((idx * 2) as u16, *inst) {
                 return false;
             }
         }
         
-        self.as_read_only(0_u16, program.len() as u16)
+        // each instruction takes 2 bytes
+        self.as_read_only(0_u16, (program.len() * 2) as u16)
+    }
+
+    // write image stores the bytes of an assembled image at address 0
+    // and marks the (address, length) `read_only` ranges, the code, as
+    // read-only. The rest of the image, the data, stays writable
+    pub fn write_image(&mut self, image: &[u8], read_only: &[(u16, u16)]) -> bool {
+        // the length of the image must fit in a u16
+        if image.len() > u16::MAX as usize {
+            return false;
+        }
+
+        for (idx, byte) in image.iter().enumerate() {
+            if !self.write(idx as u16, *byte) {
+                return false;
+            }
+        }
+
+        for (addr, len) in read_only.iter() {
+            let inside = (*addr as usize + *len as usize) <= image.len();
+            if !inside || !self.as_read_only(*addr, *len) {
+                return false;
+            }
+        }
+
+        true
     }
 
     pub fn as_read_only(&mut self, addr: u16, len: u16) -> bool {
@@ -145,3 +198,77 @@ impl Addressable for LinearMemory {
         }
     }
 }
+
+impl Snapshot for LinearMemory {
+    fn save(&self, w: &mut SnapshotWriter) {
+        w.u32(self.size as u32);
+        w.bytes(&self.bytes);
+        w.u32(self.read_only.len() as u32);
+        for (addr, len) in self.read_only.iter() {
+            w.u16(*addr);
+            w.u16(*len);
+        }
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let size = r.u32()? as usize;
+        let bytes = r.bytes(size)?.to_vec();
+
+        let regions = r.u32()?;
+        let mut read_only = vec![];
+        for _ in 0..regions {
+            read_only.push((r.u16()?, r.u16()?));
+        }
+
+        self.bytes = bytes;
+        self.size = size;
+        self.read_only = read_only;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Addressable, LinearMemory};
+
+    #[test]
+    fn write_program_protects_the_whole_program() {
+        let program = [0x1234, 0x5678, 0x9ABC];
+
+        // the region covers 2 bytes per instruction
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_program(&program));
+        assert_eq!(mem.read2(4), Some(0x9ABC));
+        assert!(!mem.write(0, 0));
+        assert!(!mem.write(program.len() as u16 * 2 - 1, 0));
+        assert!(mem.write(program.len() as u16 * 2, 0));
+    }
+
+    #[test]
+    fn write_image_protects_only_the_given_ranges() {
+        let image = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let mut mem = LinearMemory::new(1024);
+        assert!(mem.write_image(&image, &[(0, 2), (4, 2)]));
+        assert_eq!(mem.read2(6), Some(0x0807));
+        assert!(!mem.write(1, 0));
+        assert!(mem.write(2, 0));
+        assert!(!mem.write(5, 0));
+        assert!(mem.write(7, 0));
+
+        // the ranges must sit inside the image
+        let mut mem = LinearMemory::new(1024);
+        assert!(!mem.write_image(&image, &[(6, 4)]));
+    }
+
+    #[test]
+    fn write_program_refuses_a_full_address_space() {
+        let mut mem = LinearMemory::new(1 << 16);
+        assert!(!mem.write_program(&vec![0; 1 << 15]));
+        assert!(mem.write(0, 1));
+
+        assert!(mem.write_program(&vec![0; (1 << 15) - 1]));
+        assert!(!mem.write(0xFFFD, 1));
+        assert!(mem.write(0xFFFE, 1));
+    }
+}
@@ -1,10 +1,16 @@
-use crate::{devices::Device, memory::{Addressable, LinearMemory}};
+use crate::{
+    devices::Device,
+    interrupts::{InterruptController, IrqLine},
+    memory::{Addressable, LinearMemory},
+    snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter},
+};
 
 pub struct DeviceBus {
     // maps memory regions to devices
     // (start addr, end addr, device index)
     mmap: Vec<(u16, u16, usize)>,
     devices: Vec<Box<dyn Device>>,
+    interrupts: InterruptController,
 }
 
 impl DeviceBus {
@@ -12,9 +18,16 @@ impl DeviceBus {
         Self {
             mmap: Vec::new(),
             devices: Vec::new(),
+            interrupts: InterruptController::new(),
         }
     }
 
+    // irq_line hands out the handle a device should use
+    // to raise the given interrupt line
+    pub fn irq_line(&self, line: u8) -> Result<IrqLine, String> {
+        self.interrupts.line(line)
+    }
+
     pub fn register_device<D: Device + 'static>(
         &mut self,
         device: D,
@@ -65,6 +78,64 @@ impl DeviceBus {
 
         false
     }
+
+    pub fn clock(&mut self, cycles: u64) {
+        for device in self.devices.iter_mut() {
+            device.clock(cycles);
+        }
+    }
+
+    fn device_at(&mut self, start_addr: u16) -> Option<&mut Box<dyn Device>> {
+        let (_, _, dev_idx) = self.mmap.iter().find(|(start, _, _)| *start == start_addr)?;
+        self.devices.get_mut(*dev_idx)
+    }
+}
+
+impl Snapshot for DeviceBus {
+    // devices are identified by their start address, the
+    // same devices must be registered before restoring
+    fn save(&self, w: &mut SnapshotWriter) {
+        w.u16(self.interrupts.pending());
+
+        let states: Vec<(u16, Vec<u8>)> = self
+            .mmap
+            .iter()
+            .filter_map(|(start, _, dev_idx)| Some((*start, self.devices[*dev_idx].save_state()?)))
+            .collect();
+
+        w.u16(states.len() as u16);
+        for (start, state) in states {
+            w.u16(start);
+            w.u32(state.len() as u32);
+            w.bytes(&state);
+        }
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let pending = r.u16()?;
+
+        let count = r.u16()?;
+        let mut states = vec![];
+        for _ in 0..count {
+            let start = r.u16()?;
+            let len = r.u32()? as usize;
+            states.push((start, r.bytes(len)?));
+        }
+
+        for (addr, state) in states {
+            let device = self.device_at(addr).ok_or_else(|| SnapshotError::Device {
+                addr,
+                reason: "no device registered".to_string(),
+            })?;
+
+            device
+                .restore_state(state)
+                .map_err(|reason| SnapshotError::Device { addr, reason })?;
+        }
+
+        self.interrupts.set_pending(pending);
+        Ok(())
+    }
 }
 
 pub struct MemoryWithDevices {
@@ -88,6 +159,10 @@ impl MemoryWithDevices {
     ) -> Result<(), String> {
         self.device_bus.register_device(device, start_addr, size)
     }
+
+    pub fn irq_line(&self, line: u8) -> Result<IrqLine, String> {
+        self.device_bus.irq_line(line)
+    }
 }
 
 impl Addressable for MemoryWithDevices {
@@ -106,4 +181,29 @@ impl Addressable for MemoryWithDevices {
             self.linear_memory.write(addr, value)
         }
     }
+
+    fn take_interrupt(&mut self) -> Option<u8> {
+        self.device_bus.interrupts.take()
+    }
+
+    fn clock(&mut self, cycles: u64) {
+        self.device_bus.clock(cycles);
+    }
+
+    // devices may change their values by themselves
+    fn is_cacheable(&self, addr: u16) -> bool {
+        self.device_bus.find_service(addr).is_none()
+    }
+}
+
+impl Snapshot for MemoryWithDevices {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.linear_memory.save(w);
+        self.device_bus.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.linear_memory.restore(r)?;
+        self.device_bus.restore(r)
+    }
 }
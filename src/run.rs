@@ -0,0 +1,59 @@
+use std::{collections::HashSet, fmt, time::Instant};
+
+use crate::machine::VmError;
+
+// RunConfig bounds `Machine::run`, every limit is optional
+// and the default runs until the machine stops on its own
+#[derive(Debug, Clone, Default)]
+pub struct RunConfig {
+    // amount of instructions the run may execute
+    pub max_instructions: Option<u64>,
+    // the run stops before executing the instruction at any of
+    // these addresses, except for the first one of the run so
+    // a stopped run can be resumed
+    pub breakpoints: HashSet<u16>,
+    // checked every DEADLINE_INTERVAL instructions
+    pub deadline: Option<Instant>,
+    // runs with the block engine (see `Machine::step_block`),
+    // the limits above are still checked at every instruction
+    pub blocks: bool,
+}
+
+impl RunConfig {
+    pub fn with_budget(max_instructions: u64) -> Self {
+        Self {
+            max_instructions: Some(max_instructions),
+            ..Self::default()
+        }
+    }
+}
+
+// how often (in instructions) the deadline is checked, reading
+// the clock at every instruction would dominate the run
+pub(crate) const DEADLINE_INTERVAL: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // the halt bit of FLAGS was set
+    Halted,
+    BudgetExhausted,
+    // PC reached the breakpoint, the instruction was not executed
+    Breakpoint(u16),
+    DeadlineExceeded,
+    Fault(VmError),
+    // a DBG instruction was executed, holds its tag
+    DebugTrap(u8),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::BudgetExhausted => write!(f, "instruction budget exhausted"),
+            StopReason::Breakpoint(pc) => write!(f, "breakpoint at {:#06x}", pc),
+            StopReason::DeadlineExceeded => write!(f, "deadline exceeded"),
+            StopReason::Fault(err) => write!(f, "error: {}", err),
+            StopReason::DebugTrap(tag) => write!(f, "debug trap #{}", tag),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+// A snapshot captures the whole state of a machine so a program can
+// be resumed later. Every value is little endian:
+//
+//     magic "RV16SNAP" | version (u16) | machine | memory
+//
+//     machine:           registers (8 x u16) | cycles (u64)
+//                        | div trap handler (u8 present, u16)
+//                        | stack region (u8 present, u16 limit, u16 base)
+//                        | exit code (u8 present, u16)
+//                        | supervisor regions (u16) | (start u16, end u16)...
+//     LinearMemory:      size (u32) | bytes | regions (u32) | (addr u16, len u16)...
+//     MemoryWithDevices: LinearMemory | pending irqs (u16)
+//                        | devices (u16) | (start addr u16, len u32, state)...
+//
+// only the devices that opt in (see `Device::save_state`) are stored
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RV16SNAP";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    // the snapshot ended before all the state was read
+    Truncated,
+    // there are bytes left after the whole state was read
+    TrailingBytes,
+    // the device at `addr` is missing or rejected its state
+    Device { addr: u16, reason: String },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::InvalidMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingBytes => write!(f, "snapshot has trailing bytes"),
+            SnapshotError::Device { addr, reason } => {
+                write!(f, "device at {:#06x}: {}", addr, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// Snapshot is implemented by the memories that can be stored
+// and restored along with the machine
+pub trait Snapshot {
+    fn save(&self, w: &mut SnapshotWriter);
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), SnapshotError>;
+}
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos.checked_add(n).ok_or(SnapshotError::Truncated)?;
+        let bytes = self.bytes.get(self.pos..end).ok_or(SnapshotError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
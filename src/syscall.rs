@@ -0,0 +1,151 @@
+use std::{
+    cell::RefCell,
+    io::{BufRead, Write},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::machine::Register;
+
+// numbers served by StdSyscalls, the arguments are passed in
+// A, B and C and the result is returned in A (and B)
+//
+//     SYSCALL #0  exit         A = exit code
+//     SYSCALL #1  print string A = address of a NUL terminated string
+//     SYSCALL #2  print int    A = value, printed as unsigned decimal
+//     SYSCALL #3  read line    A = buffer, B = buffer size
+//                              -> A = bytes stored (0xFFFF at end of input
+//                              or when the buffer can't be written)
+//     SYSCALL #4  get time     -> A = low, B = high 16 bits of the
+//                              seconds since the UNIX epoch
+pub const SYS_EXIT: u8 = 0;
+pub const SYS_PRINT_STRING: u8 = 1;
+pub const SYS_PRINT_INT: u8 = 2;
+pub const SYS_READ_LINE: u8 = 3;
+pub const SYS_TIME: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallAction {
+    // the call was served, the guest resumes after the SYSCALL
+    Done,
+    // the handler does not serve the number, it goes to the
+    // guest kernel through the syscall vector
+    Unhandled,
+    // halts the machine with the exit code
+    Exit(u16),
+}
+
+// SyscallContext is the view of the machine a handler gets, memory
+// accesses behave as the ones of the guest instructions: a user mode
+// caller can't write the supervisor memory, those writes return false
+pub trait SyscallContext {
+    fn register(&self, reg: Register) -> u16;
+    fn set_register(&mut self, reg: Register, value: u16);
+    fn read(&mut self, addr: u16) -> Option<u8>;
+    fn write(&mut self, addr: u16, value: u8) -> bool;
+}
+
+// SyscallHandler serves the SYSCALL instructions on the host,
+// see `Machine::set_syscall_handler`
+pub trait SyscallHandler {
+    fn handle(&mut self, number: u8, ctx: &mut dyn SyscallContext) -> SyscallAction;
+}
+
+// a shared handler stays reachable by the host while the machine owns a handle
+impl<H: SyscallHandler> SyscallHandler for Rc<RefCell<H>> {
+    fn handle(&mut self, number: u8, ctx: &mut dyn SyscallContext) -> SyscallAction {
+        self.borrow_mut().handle(number, ctx)
+    }
+}
+
+// StdSyscalls implements the standard services over any
+// reader and writer (stdin and stdout in the vm binary)
+pub struct StdSyscalls<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> StdSyscalls<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    fn print_string(&mut self, ctx: &mut dyn SyscallContext) {
+        let mut addr = ctx.register(Register::A);
+        let mut text = vec![];
+        while let Some(byte) = ctx.read(addr).filter(|byte| *byte != 0) {
+            text.push(byte);
+            let Some(next) = addr.checked_add(1) else {
+                break;
+            };
+            addr = next;
+        }
+
+        let _ = self.output.write_all(&text);
+        let _ = self.output.flush();
+    }
+
+    fn print_int(&mut self, ctx: &mut dyn SyscallContext) {
+        let _ = write!(self.output, "{}", ctx.register(Register::A));
+        let _ = self.output.flush();
+    }
+
+    // the line is stored without its line break and followed
+    // by a NUL, truncated to fit in the buffer. A refused write
+    // (read-only or supervisor memory) stops the copy there
+    fn read_line(&mut self, ctx: &mut dyn SyscallContext) {
+        let buffer = ctx.register(Register::A);
+        let size = ctx.register(Register::B);
+
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                ctx.set_register(Register::A, 0xFFFF);
+                return;
+            }
+            Ok(_) => {}
+        }
+
+        let line = line.trim_end_matches(['\n', '\r']).as_bytes();
+        let len = line.len().min(size.saturating_sub(1) as usize);
+
+        if size > 0 {
+            for (offset, byte) in line[..len].iter().chain([&0]).enumerate() {
+                if !ctx.write(buffer.wrapping_add(offset as u16), *byte) {
+                    ctx.set_register(Register::A, 0xFFFF);
+                    return;
+                }
+            }
+        }
+
+        ctx.set_register(Register::A, len as u16);
+    }
+
+    fn time(&mut self, ctx: &mut dyn SyscallContext) {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+
+        ctx.set_register(Register::A, secs as u16);
+        ctx.set_register(Register::B, (secs >> 16) as u16);
+    }
+}
+
+impl<R: BufRead, W: Write> SyscallHandler for StdSyscalls<R, W> {
+    fn handle(&mut self, number: u8, ctx: &mut dyn SyscallContext) -> SyscallAction {
+        match number {
+            SYS_EXIT => return SyscallAction::Exit(ctx.register(Register::A)),
+            SYS_PRINT_STRING => self.print_string(ctx),
+            SYS_PRINT_INT => self.print_int(ctx),
+            SYS_READ_LINE => self.read_line(ctx),
+            SYS_TIME => self.time(ctx),
+            _ => return SyscallAction::Unhandled,
+        }
+
+        SyscallAction::Done
+    }
+}
@@ -0,0 +1,142 @@
+use crate::machine::Instruction;
+
+// CycleCosts is the timing model of the machine, it holds how many
+// cycles each instruction takes. The defaults charge 1 cycle for
+// register only work, plus 1 cycle per memory access and per
+// change of the program flow:
+//
+//     MOV A, #1   -> 1 cycle
+//     LDR A, B    -> 2 cycles
+//     CALL #128   -> 3 cycles (jump + push of the return address)
+//
+// the costs are deterministic, the same program always
+// consumes the same amount of cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleCosts {
+    pub noop: u32,
+    pub mov: u32,
+    pub mov_shift: u32,
+    pub arith: u32,
+    pub arith_reg_reg: u32,
+    pub ldr_str: u32,
+    pub ldb_stb: u32,
+    pub ldb_signed: u32,
+    pub cpy: u32,
+    pub jmp: u32,
+    pub cond_jmp: u32,
+    pub cmp: u32,
+    pub call_ret: u32,
+    pub call: u32,
+    pub ret: u32,
+    pub bitwise: u32,
+    pub rti: u32,
+    pub push: u32,
+    pub pop: u32,
+    pub enter: u32,
+    pub leave: u32,
+    pub breakpoint: u32,
+    pub syscall: u32,
+    pub halt: u32,
+    // charged on top of the instruction when an interrupt
+    // or a trap enters its handler
+    pub interrupt: u32,
+}
+
+impl CycleCosts {
+    // uniform charges the same amount of cycles for every
+    // instruction, useful to count executed instructions
+    pub fn uniform(cycles: u32) -> Self {
+        Self {
+            noop: cycles,
+            mov: cycles,
+            mov_shift: cycles,
+            arith: cycles,
+            arith_reg_reg: cycles,
+            ldr_str: cycles,
+            ldb_stb: cycles,
+            ldb_signed: cycles,
+            cpy: cycles,
+            jmp: cycles,
+            cond_jmp: cycles,
+            cmp: cycles,
+            call_ret: cycles,
+            call: cycles,
+            ret: cycles,
+            bitwise: cycles,
+            rti: cycles,
+            push: cycles,
+            pop: cycles,
+            enter: cycles,
+            leave: cycles,
+            breakpoint: cycles,
+            syscall: cycles,
+            halt: cycles,
+            interrupt: 0,
+        }
+    }
+
+    pub fn cost(&self, inst: &Instruction) -> u32 {
+        match inst {
+            Instruction::Noop => self.noop,
+            Instruction::Mov(..) => self.mov,
+            Instruction::MovShift(..) => self.mov_shift,
+            Instruction::Arith(..) => self.arith,
+            Instruction::ArithRegReg(..) => self.arith_reg_reg,
+            Instruction::LdrStr(..) => self.ldr_str,
+            Instruction::LdbStb(..) => self.ldb_stb,
+            Instruction::LdbSigned(..) => self.ldb_signed,
+            Instruction::Cpy(..) => self.cpy,
+            Instruction::Jmp(..) => self.jmp,
+            Instruction::CondJmp(..) => self.cond_jmp,
+            Instruction::Cmp(..) => self.cmp,
+            Instruction::CallRet(..) => self.call_ret,
+            Instruction::Call(..) => self.call,
+            Instruction::Ret => self.ret,
+            Instruction::Bitwise(..) => self.bitwise,
+            Instruction::Rti => self.rti,
+            Instruction::Push(_) => self.push,
+            Instruction::Pop(_) => self.pop,
+            Instruction::Enter(_) => self.enter,
+            Instruction::Leave => self.leave,
+            Instruction::Breakpoint(_) => self.breakpoint,
+            Instruction::Syscall(_) => self.syscall,
+            Instruction::Halt(_) => self.halt,
+        }
+    }
+}
+
+impl Default for CycleCosts {
+    fn default() -> Self {
+        Self {
+            noop: 1,
+            mov: 1,
+            mov_shift: 1,
+            arith: 1,
+            arith_reg_reg: 1,
+            ldr_str: 2,
+            ldb_stb: 2,
+            ldb_signed: 2,
+            // reads and writes memory
+            cpy: 3,
+            jmp: 2,
+            cond_jmp: 2,
+            cmp: 1,
+            call_ret: 2,
+            call: 3,
+            ret: 3,
+            bitwise: 1,
+            // pops PC and FLAGS
+            rti: 4,
+            push: 2,
+            pop: 2,
+            enter: 3,
+            leave: 2,
+            breakpoint: 1,
+            // pushes FLAGS, PC and the number
+            syscall: 4,
+            halt: 1,
+            // pushes FLAGS and the return address
+            interrupt: 4,
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use crate::machine::{Instruction, Register};
+
+// A trace file is a header followed by one record per step, every
+// value is little endian:
+//
+//     magic "RV16TRCE" | version (u16) | record...
+//
+//     record: step (u64) | pc (u16) | raw (u16)
+//             | changed registers (u8) | (register u8, old u16, new u16)...
+//             | memory accesses (u16) | (write u8, addr u16, value u8)...
+//
+// the decoded instruction is not stored, readers decode `raw`
+pub const TRACE_MAGIC: &[u8; 8] = b"RV16TRCE";
+pub const TRACE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub is_write: bool,
+}
+
+// TraceEvent is what a single step did, the registers holds
+// only the ones that changed as (register, old, new)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub step: u64,
+    pub pc: u16,
+    pub raw: u16,
+    // None when the raw word is not a valid instruction
+    pub instruction: Option<Instruction>,
+    pub registers: Vec<(Register, u16, u16)>,
+    pub memory: Vec<MemAccess>,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inst = match &self.instruction {
+            Some(inst) => inst.to_string(),
+            None => "???".to_string(),
+        };
+        write!(f, "#{:<8} {:#06x} {:#06x} {:<20}", self.step, self.pc, self.raw, inst)?;
+
+        for (reg, old, new) in self.registers.iter() {
+            write!(f, " {}: {:#06x} -> {:#06x}", reg.to_string(), old, new)?;
+        }
+
+        for access in self.memory.iter() {
+            let kind = if access.is_write { "W" } else { "R" };
+            write!(f, " {}[{:#06x}]={:#04x}", kind, access.addr, access.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    // the file ended in the middle of the header or a record
+    Truncated,
+    InvalidRegister(u8),
+    // the kind of a memory access is neither 0 (read) nor 1 (write)
+    InvalidAccess(u8),
+    Io(io::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::InvalidMagic => write!(f, "not a trace file"),
+            TraceError::UnsupportedVersion(version) => {
+                write!(f, "unsupported trace version {}", version)
+            }
+            TraceError::Truncated => write!(f, "trace is truncated"),
+            TraceError::InvalidRegister(reg) => write!(f, "invalid register {}", reg),
+            TraceError::InvalidAccess(kind) => write!(f, "invalid memory access kind {}", kind),
+            TraceError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(err: io::Error) -> Self {
+        TraceError::Io(err)
+    }
+}
+
+// TraceSink receives an event after every executed step
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent);
+}
+
+// keeps the events in memory, useful for tests and tools
+impl TraceSink for Vec<TraceEvent> {
+    fn record(&mut self, event: &TraceEvent) {
+        self.push(event.clone());
+    }
+}
+
+// a shared sink stays readable by the host while the machine owns a handle
+impl<S: TraceSink> TraceSink for Rc<RefCell<S>> {
+    fn record(&mut self, event: &TraceEvent) {
+        self.borrow_mut().record(event);
+    }
+}
+
+// TraceWriter stores the events in the trace file format, the
+// first write error is kept and the following events are dropped
+pub struct TraceWriter<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut out: W) -> Self {
+        let mut header = TRACE_MAGIC.to_vec();
+        header.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        let error = out.write_all(&header).err();
+
+        Self { out, error }
+    }
+
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> TraceSink for TraceWriter<W> {
+    fn record(&mut self, event: &TraceEvent) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut record = vec![];
+        record.extend_from_slice(&event.step.to_le_bytes());
+        record.extend_from_slice(&event.pc.to_le_bytes());
+        record.extend_from_slice(&event.raw.to_le_bytes());
+
+        record.push(event.registers.len() as u8);
+        for (reg, old, new) in event.registers.iter() {
+            record.push(*reg as u8);
+            record.extend_from_slice(&old.to_le_bytes());
+            record.extend_from_slice(&new.to_le_bytes());
+        }
+
+        record.extend_from_slice(&(event.memory.len() as u16).to_le_bytes());
+        for access in event.memory.iter() {
+            record.push(access.is_write as u8);
+            record.extend_from_slice(&access.addr.to_le_bytes());
+            record.push(access.value);
+        }
+
+        self.error = self.out.write_all(&record).err();
+    }
+}
+
+// read_trace parses a whole trace file
+pub fn read_trace<R: Read>(mut input: R) -> Result<Vec<TraceEvent>, TraceError> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+
+    let mut r = TraceReader { bytes: &bytes, pos: 0 };
+    if r.bytes(TRACE_MAGIC.len()).ok() != Some(&TRACE_MAGIC[..]) {
+        return Err(TraceError::InvalidMagic);
+    }
+
+    let version = r.u16()?;
+    if version != TRACE_VERSION {
+        return Err(TraceError::UnsupportedVersion(version));
+    }
+
+    let mut events = vec![];
+    while !r.is_empty() {
+        let step = r.u64()?;
+        let pc = r.u16()?;
+        let raw = r.u16()?;
+
+        let mut registers = vec![];
+        for _ in 0..r.u8()? {
+            let reg = r.u8()?;
+            let reg = Register::try_from(reg as usize).map_err(|_| TraceError::InvalidRegister(reg))?;
+            registers.push((reg, r.u16()?, r.u16()?));
+        }
+
+        let mut memory = vec![];
+        for _ in 0..r.u16()? {
+            let is_write = match r.u8()? {
+                0 => false,
+                1 => true,
+                kind => return Err(TraceError::InvalidAccess(kind)),
+            };
+            memory.push(MemAccess {
+                is_write,
+                addr: r.u16()?,
+                value: r.u8()?,
+            });
+        }
+
+        events.push(TraceEvent {
+            step,
+            pc,
+            raw,
+            instruction: Instruction::try_from(raw).ok(),
+            registers,
+            memory,
+        });
+    }
+
+    Ok(events)
+}
+
+// TraceReader reads the little endian values of a trace file,
+// running out of bytes is a truncated trace
+struct TraceReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TraceReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], TraceError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(TraceError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, TraceError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TraceError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, TraceError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+}
+
+// TraceFilter selects events, every field that is set must match
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pc: Option<u16>,
+    // mnemonic of the instruction, as the assembler writes it (ADD, LDR...)
+    pub mnemonic: Option<String>,
+    // events that changed the register
+    pub register: Option<Register>,
+    // events that read or wrote the address
+    pub addr: Option<u16>,
+    pub from_step: Option<u64>,
+    pub to_step: Option<u64>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, event: &TraceEvent) -> bool {
+        let mnemonic = |expected: &String| {
+            event.instruction.is_some_and(|inst| {
+                inst.to_string()
+                    .split_whitespace()
+                    .next()
+                    .is_some_and(|found| found.eq_ignore_ascii_case(expected))
+            })
+        };
+
+        self.pc.is_none_or(|pc| event.pc == pc)
+            && self.mnemonic.as_ref().is_none_or(mnemonic)
+            && self
+                .register
+                .is_none_or(|reg| event.registers.iter().any(|(changed, _, _)| *changed == reg))
+            && self
+                .addr
+                .is_none_or(|addr| event.memory.iter().any(|access| access.addr == addr))
+            && self.from_step.is_none_or(|from| event.step >= from)
+            && self.to_step.is_none_or(|to| event.step <= to)
+    }
+}
+
+// first_divergence returns the index of the first event that differs
+// between both traces (ignoring the step number), a trace that ends
+// earlier diverges at its end
+pub fn first_divergence(lhs: &[TraceEvent], rhs: &[TraceEvent]) -> Option<usize> {
+    let same = |a: &TraceEvent, b: &TraceEvent| {
+        a.pc == b.pc && a.raw == b.raw && a.registers == b.registers && a.memory == b.memory
+    };
+
+    match lhs.iter().zip(rhs.iter()).position(|(a, b)| !same(a, b)) {
+        Some(idx) => Some(idx),
+        None if lhs.len() != rhs.len() => Some(lhs.len().min(rhs.len())),
+        None => None,
+    }
+}
+
+// Tracer is the machine side of tracing, it collects what
+// the current step does and hands the event to the sink
+pub(crate) struct Tracer {
+    sink: Box<dyn TraceSink>,
+    steps: u64,
+    fetched: Option<(u16, u16, Option<Instruction>)>,
+    memory: Vec<MemAccess>,
+}
+
+impl Tracer {
+    pub(crate) fn new(sink: Box<dyn TraceSink>) -> Self {
+        Self {
+            sink,
+            steps: 0,
+            fetched: None,
+            memory: vec![],
+        }
+    }
+
+    pub(crate) fn fetched(&mut self, pc: u16, raw: u16, instruction: Option<Instruction>) {
+        self.fetched = Some((pc, raw, instruction));
+    }
+
+    pub(crate) fn access(&mut self, addr: u16, value: u8, is_write: bool) {
+        self.memory.push(MemAccess {
+            addr,
+            value,
+            is_write,
+        });
+    }
+
+    // finish emits the event of the step, steps that did
+    // not fetch an instruction are not traced
+    pub(crate) fn finish(&mut self, before: &[u16; 8], after: &[u16; 8]) {
+        let memory = std::mem::take(&mut self.memory);
+        let Some((pc, raw, instruction)) = self.fetched.take() else {
+            return;
+        };
+
+        let registers = before
+            .iter()
+            .zip(after.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .filter_map(|(idx, (old, new))| Some((Register::try_from(idx).ok()?, *old, *new)))
+            .collect();
+
+        self.sink.record(&TraceEvent {
+            step: self.steps,
+            pc,
+            raw,
+            instruction,
+            registers,
+            memory,
+        });
+        self.steps += 1;
+    }
+}
@@ -13,8 +13,7 @@ JMP int_to_str
 
 return_to_loop:
 MOV A, #2
-MOV B, #482
-MSL B, [#2 #7]
+LI B, #0xF102
 STR A, B
 
 ADD FLAGS, #1
@@ -26,8 +25,7 @@ int_to_str:
 GT A, #9
 CJP int_to_str_bef_loop
 
-MOV B, #480
-MSL B, [#0 #7]
+LI B, #0xF000
 
 ADD A, #48
 STB A, B
@@ -19,13 +19,11 @@ return_to_loop:
 
 ; Mostrar numero no terminal
 MOV B, #2
-MOV C, #482
-MSL C, [#2 #7]
+LI C, #0xF102
 STR B, C
 
 ; Mover o cursor para a proxima linha
-MOV C, #482
-MSL C, [#1 #7]
+LI C, #0xF101
 LDB B, C
 ADD B, #1
 STB B, C
@@ -1,30 +1,22 @@
-MOV A, #72
-MOV B, #0x0F
-MSL B, [#0 #7]
-MSL B, [#0 #5]
-STR A, B
+; copies the message into the terminal buffer
+LI A, message
+LI B, #0xF000
 
-MOV A, #101
-ADD B, #1
-STR A, B
-    
-MOV A, #108
+print:
+LDB C, A
+EQ C, #0
+CJP flush
+STB C, B
+ADD A, #1
 ADD B, #1
-STR A, B
-
-MOV A, #108
-ADD B, #1
-STR A, B
-
-MOV A, #111
-ADD B, #1
-STR A, B
+JMP print
 
+flush:
 MOV A, #2
-MOV B, #0x0F
-MSL B, [#1 #4]
-MSL B, [#1 #7]
-MSL B, [#0 #1]
+LI B, #0xF102
 STR A, B
 
 ADD FLAGS, #1
+
+message:
+.asciz "Hello"
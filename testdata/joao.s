@@ -1,6 +1,5 @@
 MOV A, #74
-MOV B, #480
-MSL B, [#0 #7]
+LI B, #0xF000
 STR A, B
 
 MOV A, #111
@@ -17,8 +16,7 @@ ADD B, #1
 STR A, B
 
 MOV A, #2
-MOV B, #482
-MSL B, [#2 #7]
+LI B, #0xF102
 STR A, B
 
 ADD FLAGS, #1
\ No newline at end of file
@@ -3,7 +3,7 @@ ADD FLAGS, #2
 MOV A, #104
 MOV B, #44
 
-CALL min_max
+LCALL min_max
 
 init_loop:
 ; condicao do loop
@@ -75,6 +75,8 @@ ADD FLAGS, #1
 ; esta funcao armazena na stack
 ; em ordem decrescente os valores
 ; do REG A e do REG B
+; (usa LCALL/LRET pois deixa os
+; valores no topo da stack)
 
 min_max:
 GT A, B
@@ -87,7 +89,7 @@ STR A, SP
 
 SUB SP, #2
 STR B, SP
-RET
+LRET
 
 store_b_a:
 SUB SP, #2
@@ -95,7 +97,7 @@ STR B, SP
 
 SUB SP, #2
 STR A, SP
-RET
+LRET
 
 ; ====== FUNCAO PRINT ======
 ; esta funcao mostra o MMC